@@ -0,0 +1,639 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use log::info;
+use mongodb::{Client, Collection};
+use serde::{Deserialize, Deserializer};
+
+use crate::fetching_pipeline::Pipeline;
+use crate::ft_mongodb_app_indexor::dead_letters_name;
+use crate::ft_mongodb_collections::{self, QUEUES};
+use crate::run_budget::{RunBudget, parse_duration};
+
+/// File read when neither `--config` nor `FT_CONFIG` names one.
+pub const DEFAULT_CONFIG_PATH: &str = "ft_connections.toml";
+
+static CONFIG: OnceLock<AppConfig> = OnceLock::new();
+
+/// Everything the binary can be configured with. Each layer overrides the
+/// previous one: defaults, then the TOML file, then environment variables,
+/// then command-line flags.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub mongodb: MongoConfig,
+    /// Logical collection name, one of `ft_mongodb_collections`, to the name
+    /// actually used in MongoDB before `mongodb.collection_prefix`.
+    pub collections: HashMap<String, String>,
+    pub credentials: CredentialsConfig,
+    pub pacing: PacingConfig,
+    pub budget: BudgetConfig,
+    pub quota: QuotaConfig,
+    pub slimming: SlimmingConfig,
+    pub bulk: BulkConfig,
+    pub features: FeaturesConfig,
+    /// Stages walked by the dispatcher, the bundled pipeline.toml when unset.
+    pub pipeline: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoConfig {
+    pub uri: Option<String>,
+    /// Data fetched from the 42 API.
+    pub data_database: String,
+    /// Queues, locks, workers and the other bookkeeping of the binary.
+    pub app_database: String,
+    /// Put in front of every collection name, like `staging_`, so several
+    /// environments can share the same databases.
+    pub collection_prefix: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub profil_id: Option<String>,
+    pub profil_secret: Option<String>,
+    pub location_id: Option<String>,
+    pub location_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacingConfig {
+    /// Seconds between the starts of two double fetches.
+    pub time_between_requests: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetConfig {
+    pub fetches: Option<u32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub deadline: Option<Duration>,
+}
+
+/// Share of the request slots each resource gets when several are fetched
+/// at once. A resource with nothing to fetch leaves its share to the others,
+/// a weight of 0 keeps it out.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub profiles: u32,
+    pub locations: u32,
+    pub events: u32,
+    pub participations: u32,
+}
+
+/// Fields of an API document worth storing, as dotted paths that go through
+/// nested documents and every element of arrays. When `keep` is set only
+/// those paths stay, then the `drop` paths are removed. The `id` of the
+/// document is always kept.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlimRule {
+    pub keep: Option<Vec<String>>,
+    pub drop: Vec<String>,
+}
+
+/// What each resource stores of the documents the 42 API returns, applied
+/// before writing and before changes are derived.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlimmingConfig {
+    pub profiles: SlimRule,
+    pub locations: SlimRule,
+    pub events: SlimRule,
+    /// The event stored with each participation.
+    pub participations: SlimRule,
+}
+
+/// When the data writes buffered by `ft_mongodb_write_buffer` are sent in
+/// one `bulk_write`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulkConfig {
+    /// Buffered writes that trigger a flush, 1 to write each one at once.
+    pub max_writes: usize,
+    /// Seconds the oldest buffered write may wait.
+    pub max_age: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    /// Register in `workers` and send heartbeats while fetching.
+    pub register_worker: bool,
+    /// Sweep stale documents back into their queues before dispatching.
+    pub refresh_before_run: bool,
+    /// Fetch and transform as usual but print the documents instead of
+    /// writing them, and peek at the queues instead of claiming from them.
+    pub dry_run: bool,
+    /// Create the missing declared indexes when the binary starts.
+    pub ensure_indexes: bool,
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        MongoConfig {
+            uri: None,
+            data_database: "42".to_string(),
+            app_database: "application".to_string(),
+            collection_prefix: String::new(),
+        }
+    }
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        PacingConfig {
+            time_between_requests: 3,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            profiles: 1,
+            locations: 1,
+            events: 1,
+            participations: 1,
+        }
+    }
+}
+
+impl QuotaConfig {
+    /// Every resource with a non-zero weight.
+    pub fn weights(&self) -> Vec<(Pipeline, u32)> {
+        [
+            (Pipeline::Profiles, self.profiles),
+            (Pipeline::Locations, self.locations),
+            (Pipeline::Events, self.events),
+            (Pipeline::Participations, self.participations),
+        ]
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .collect()
+    }
+}
+
+impl Default for SlimmingConfig {
+    fn default() -> Self {
+        let drop = |paths: &[&str]| SlimRule {
+            keep: None,
+            drop: paths.iter().map(|path| path.to_string()).collect(),
+        };
+        SlimmingConfig {
+            // Every size of the picture, `image.link` is enough.
+            profiles: drop(&["image.versions"]),
+            // The whole user and project, already known by their ids.
+            locations: drop(&["user", "project"]),
+            events: SlimRule::default(),
+            participations: SlimRule::default(),
+        }
+    }
+}
+
+impl SlimmingConfig {
+    pub fn rule(&self, pipeline: Pipeline) -> &SlimRule {
+        match pipeline {
+            Pipeline::Profiles => &self.profiles,
+            Pipeline::Locations => &self.locations,
+            Pipeline::Events => &self.events,
+            Pipeline::Participations => &self.participations,
+        }
+    }
+
+    fn paths(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        [
+            ("profiles", &self.profiles),
+            ("locations", &self.locations),
+            ("events", &self.events),
+            ("participations", &self.participations),
+        ]
+        .into_iter()
+        .flat_map(|(resource, rule)| {
+            let keep = rule.keep.iter().flatten().map(move |path| (resource, path));
+            keep.chain(rule.drop.iter().map(move |path| (resource, path)))
+        })
+    }
+}
+
+impl Default for BulkConfig {
+    fn default() -> Self {
+        BulkConfig {
+            max_writes: 500,
+            max_age: 5,
+        }
+    }
+}
+
+impl BulkConfig {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age)
+    }
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        FeaturesConfig {
+            register_worker: true,
+            refresh_before_run: false,
+            dry_run: false,
+            ensure_indexes: true,
+        }
+    }
+}
+
+/// The four secrets of the two 42 applications, all present.
+pub struct Credentials<'a> {
+    pub profil_id: &'a str,
+    pub profil_secret: &'a str,
+    pub location_id: &'a str,
+    pub location_secret: &'a str,
+}
+
+impl CredentialsConfig {
+    /// Every secret, or an error naming each missing one and where it can be
+    /// set.
+    pub fn require(&self) -> Result<Credentials<'_>, Box<dyn Error>> {
+        let fields = [
+            (&self.profil_id, "profil_id", "SECRET_ID_PROFIL"),
+            (&self.profil_secret, "profil_secret", "SECRET_KEY_PROFIL"),
+            (&self.location_id, "location_id", "SECRET_ID_LOCATION"),
+            (
+                &self.location_secret,
+                "location_secret",
+                "SECRET_KEY_LOCATION",
+            ),
+        ];
+        let missing: Vec<String> = fields
+            .iter()
+            .filter(|(value, _, _)| value.is_none())
+            .map(|(_, key, env_name)| {
+                format!(
+                    "credentials.{} is missing, set it in the config file, {} or --{}",
+                    key,
+                    env_name,
+                    key.replace('_', "-")
+                )
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(missing.join("\n").into());
+        }
+        Ok(Credentials {
+            profil_id: self.profil_id.as_deref().unwrap_or_default(),
+            profil_secret: self.profil_secret.as_deref().unwrap_or_default(),
+            location_id: self.location_id.as_deref().unwrap_or_default(),
+            location_secret: self.location_secret.as_deref().unwrap_or_default(),
+        })
+    }
+}
+
+impl AppConfig {
+    /// Defaults overridden by the file at `path`, by `FT_CONFIG`, or by
+    /// `DEFAULT_CONFIG_PATH` when it exists, then by the environment.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let env_path = std::env::var("FT_CONFIG").ok().map(PathBuf::from);
+        let path = path.map(Path::to_path_buf).or(env_path).or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_PATH);
+            default.exists().then_some(default)
+        });
+        let mut config = match path {
+            Some(path) => {
+                info!("Loading configuration from {}.", path.display());
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+                Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            None => AppConfig::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Overrides with the variables `lookup` knows about.
+    pub fn apply_env(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let set = |target: &mut Option<String>, name: &str| {
+            if let Some(value) = lookup(name) {
+                *target = Some(value);
+            }
+        };
+        set(&mut self.mongodb.uri, "MONGODB_URI");
+        set(&mut self.credentials.profil_id, "SECRET_ID_PROFIL");
+        set(&mut self.credentials.profil_secret, "SECRET_KEY_PROFIL");
+        set(&mut self.credentials.location_id, "SECRET_ID_LOCATION");
+        set(&mut self.credentials.location_secret, "SECRET_KEY_LOCATION");
+        if let Some(value) = lookup("FT_DATA_DATABASE") {
+            self.mongodb.data_database = value;
+        }
+        if let Some(value) = lookup("FT_APP_DATABASE") {
+            self.mongodb.app_database = value;
+        }
+        if let Some(value) = lookup("FT_COLLECTION_PREFIX") {
+            self.mongodb.collection_prefix = value;
+        }
+        if let Some(value) = lookup("FT_PIPELINE") {
+            self.pipeline = Some(PathBuf::from(value));
+        }
+        if let Some(value) = lookup("FT_TIME_BETWEEN_REQUESTS") {
+            self.pacing.time_between_requests = parse_env("FT_TIME_BETWEEN_REQUESTS", &value)?;
+        }
+        if let Some(value) = lookup("FT_FETCHES") {
+            self.budget.fetches = Some(parse_env("FT_FETCHES", &value)?);
+        }
+        if let Some(value) = lookup("FT_DEADLINE") {
+            self.budget.deadline =
+                Some(parse_duration(&value).map_err(|e| format!("FT_DEADLINE: {}", e))?);
+        }
+        if let Some(value) = lookup("FT_BULK_MAX_WRITES") {
+            self.bulk.max_writes = parse_env("FT_BULK_MAX_WRITES", &value)?;
+        }
+        if let Some(value) = lookup("FT_BULK_MAX_AGE") {
+            self.bulk.max_age = parse_env("FT_BULK_MAX_AGE", &value)?;
+        }
+        if let Some(value) = lookup("FT_REGISTER_WORKER") {
+            self.features.register_worker = parse_env("FT_REGISTER_WORKER", &value)?;
+        }
+        if let Some(value) = lookup("FT_REFRESH_BEFORE_RUN") {
+            self.features.refresh_before_run = parse_env("FT_REFRESH_BEFORE_RUN", &value)?;
+        }
+        if let Some(value) = lookup("FT_DRY_RUN") {
+            self.features.dry_run = parse_env("FT_DRY_RUN", &value)?;
+        }
+        if let Some(value) = lookup("FT_ENSURE_INDEXES") {
+            self.features.ensure_indexes = parse_env("FT_ENSURE_INDEXES", &value)?;
+        }
+        Ok(())
+    }
+
+    /// Reports every problem at once instead of failing on the first use.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let mut problems = Vec::new();
+        if self.mongodb.uri.as_deref().is_none_or(str::is_empty) {
+            problems.push(
+                "mongodb.uri is missing, set it in the config file, MONGODB_URI or --mongodb-uri"
+                    .to_string(),
+            );
+        }
+        for (key, name) in [
+            ("mongodb.data_database", &self.mongodb.data_database),
+            ("mongodb.app_database", &self.mongodb.app_database),
+        ] {
+            if !is_valid_database_name(name) {
+                problems.push(format!("{} '{}' is not a valid database name", key, name));
+            }
+        }
+        let logical_names: Vec<String> = ft_mongodb_collections::ALL
+            .iter()
+            .map(|name| name.to_string())
+            .chain(QUEUES.iter().map(|queue| dead_letters_name(queue)))
+            .collect();
+        for (logical, name) in &self.collections {
+            if !logical_names.contains(logical) {
+                problems.push(format!(
+                    "collections.{} is not a collection, use one of {}",
+                    logical,
+                    logical_names.join(", ")
+                ));
+            }
+            if name.is_empty() || name.contains('$') {
+                problems.push(format!(
+                    "collections.{} '{}' is not a valid collection name",
+                    logical, name
+                ));
+            }
+        }
+        let prefix = &self.mongodb.collection_prefix;
+        if prefix.contains(['$', '\0']) || prefix.starts_with("system.") {
+            problems.push(format!(
+                "mongodb.collection_prefix '{}' is not a valid collection name prefix",
+                prefix
+            ));
+        }
+        if self.pacing.time_between_requests == 0 {
+            problems.push("pacing.time_between_requests must be at least 1 second".to_string());
+        }
+        if self.quota.weights().is_empty() {
+            problems.push("quota needs at least one non-zero weight".to_string());
+        }
+        for (resource, path) in self.slimming.paths() {
+            if path.split('.').any(str::is_empty) {
+                problems.push(format!(
+                    "slimming.{} path '{}' is not a dotted field path",
+                    resource, path
+                ));
+            }
+        }
+        if self.bulk.max_writes == 0 {
+            problems.push("bulk.max_writes must be at least 1".to_string());
+        }
+        if self.bulk.max_age == 0 {
+            problems.push("bulk.max_age must be at least 1 second".to_string());
+        }
+        if self.budget.fetches == Some(0) {
+            problems.push("budget.fetches must be at least 1".to_string());
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(format!("Invalid configuration:\n  {}", problems.join("\n  ")).into())
+    }
+
+    /// Makes this configuration the one seen by the whole process.
+    pub fn install(self) -> Result<&'static AppConfig, Box<dyn Error>> {
+        CONFIG
+            .set(self)
+            .map_err(|_| "Configuration is already installed")?;
+        Ok(get())
+    }
+
+    /// A fresh budget, its deadline counted from now.
+    pub fn run_budget(&self) -> RunBudget {
+        RunBudget::new(self.budget.fetches, self.budget.deadline)
+    }
+}
+
+/// The installed configuration, or the defaults when none was installed,
+/// as in tests.
+pub fn get() -> &'static AppConfig {
+    CONFIG.get_or_init(AppConfig::default)
+}
+
+pub fn time_between_requests() -> Duration {
+    Duration::from_secs(get().pacing.time_between_requests)
+}
+
+/// Name used in MongoDB for the logical collection `name`: renamed by
+/// `[collections]`, then prefixed.
+pub fn collection_name(name: &str) -> String {
+    let config = get();
+    let name = config.collections.get(name).map_or(name, String::as_str);
+    format!("{}{}", config.mongodb.collection_prefix, name)
+}
+
+/// Collection `name` of the database holding the data fetched from 42.
+pub fn data_collection<T: Send + Sync>(client: &Client, name: &str) -> Collection<T> {
+    client
+        .database(&get().mongodb.data_database)
+        .collection(&collection_name(name))
+}
+
+/// Collection `name` of the bookkeeping database of the binary.
+pub fn app_collection<T: Send + Sync>(client: &Client, name: &str) -> Collection<T> {
+    client
+        .database(&get().mongodb.app_database)
+        .collection(&collection_name(name))
+}
+
+pub fn data_database_name() -> &'static str {
+    &get().mongodb.data_database
+}
+
+pub fn app_database_name() -> &'static str {
+    &get().mongodb.app_database
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Box<dyn Error>>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("{} '{}': {}", name, value, e).into())
+}
+
+fn is_valid_database_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() < 64
+        && !name
+            .chars()
+            .any(|c| matches!(c, '/' | '\\' | '.' | ' ' | '"' | '$' | '\0'))
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|value| parse_duration(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let mut config = AppConfig::parse(
+            r#"
+            [mongodb]
+            uri = "mongodb://file"
+            data_database = "42_staging"
+
+            [budget]
+            deadline = "5m"
+
+            [collections]
+            profiles = "profiles_v2"
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_env(|name| match name {
+                "MONGODB_URI" => Some("mongodb://env".to_string()),
+                "FT_FETCHES" => Some("40".to_string()),
+                "FT_DRY_RUN" => Some("true".to_string()),
+                "FT_COLLECTION_PREFIX" => Some("staging_".to_string()),
+                "FT_BULK_MAX_WRITES" => Some("100".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.mongodb.uri.as_deref(), Some("mongodb://env"));
+        assert_eq!(config.mongodb.data_database, "42_staging");
+        assert_eq!(config.mongodb.app_database, "application");
+        assert_eq!(config.budget.deadline, Some(Duration::from_secs(300)));
+        assert_eq!(config.budget.fetches, Some(40));
+        assert!(config.features.dry_run);
+        assert_eq!(config.collections["profiles"], "profiles_v2");
+        assert_eq!(config.mongodb.collection_prefix, "staging_");
+        assert_eq!(config.bulk.max_writes, 100);
+        assert_eq!(config.bulk.max_age(), Duration::from_secs(5));
+        assert!(config.validate().is_ok());
+        let example = AppConfig::parse(include_str!("../ft_connections.example.toml")).unwrap();
+        assert!(example.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_values_are_reported_together() {
+        let mut config = AppConfig::parse("[pacing]\ntime_between_requests = 0").unwrap();
+        config.mongodb.data_database = "4.2".to_string();
+        config.mongodb.collection_prefix = "system.".to_string();
+        config.slimming.events.drop = vec!["image..link".to_string()];
+        config.bulk.max_writes = 0;
+        config.quota = QuotaConfig {
+            profiles: 0,
+            locations: 0,
+            events: 0,
+            participations: 0,
+        };
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("mongodb.uri is missing"));
+        assert!(error.contains("mongodb.data_database '4.2'"));
+        assert!(error.contains("pacing.time_between_requests"));
+        assert!(error.contains("mongodb.collection_prefix 'system.'"));
+        assert!(error.contains("slimming.events path 'image..link'"));
+        assert!(error.contains("quota needs at least one non-zero weight"));
+        assert!(error.contains("bulk.max_writes must be at least 1"));
+        assert!(AppConfig::parse("[budget]\ndeadline = \"soon\"").is_err());
+        assert!(AppConfig::parse("[mongodb]\nhost = \"x\"").is_err());
+        assert!(
+            AppConfig::default()
+                .apply_env(|_| Some("many".to_string()))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_unknown_collections_are_rejected() {
+        let mut config = AppConfig::parse("[collections]\nprofile = \"profiles_v2\"").unwrap();
+        config.mongodb.uri = Some("mongodb://file".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("collections.profile is not a collection"));
+        assert!(error.contains("profiles_history"));
+        assert!(error.contains("events_ids_dead_letters"));
+        config.collections = HashMap::from([(
+            "events_ids_dead_letters".to_string(),
+            "dead_events".to_string(),
+        )]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_missing_credentials_are_named() {
+        let credentials = CredentialsConfig {
+            profil_id: Some("id".to_string()),
+            ..CredentialsConfig::default()
+        };
+        let error = credentials.require().err().unwrap().to_string();
+        assert!(!error.contains("credentials.profil_id"));
+        assert!(error.contains("credentials.location_secret is missing"));
+        assert!(error.contains("SECRET_KEY_LOCATION"));
+    }
+}
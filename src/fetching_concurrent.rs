@@ -0,0 +1,173 @@
+use std::error::Error;
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use log::{error, info};
+use mongodb::Client;
+use oauth2::AccessToken;
+use tokio::time::Instant;
+
+use crate::{
+    config,
+    fetching_pipeline::{Pipeline, fetch_once},
+    ft_mongodb_app_queue_stats,
+    run_budget::RunBudget,
+    shutdown::Shutdown,
+};
+
+/// Splits request slots between pipelines by weight, smooth weighted round
+/// robin style: picks interleave instead of coming in bursts, and only the
+/// pipelines ready at a slot compete for it, so an idle pipeline neither
+/// blocks the slot nor saves up credit for later.
+#[derive(Debug, Clone)]
+pub struct QuotaAllocator {
+    /// Each pipeline with its weight and its current credit.
+    shares: Vec<(Pipeline, u32, i64)>,
+}
+
+impl QuotaAllocator {
+    pub fn new(weights: &[(Pipeline, u32)]) -> Self {
+        QuotaAllocator {
+            shares: weights
+                .iter()
+                .map(|(pipeline, weight)| (*pipeline, *weight, 0))
+                .collect(),
+        }
+    }
+
+    pub fn pipelines(&self) -> impl Iterator<Item = Pipeline> + '_ {
+        self.shares.iter().map(|(pipeline, _, _)| *pipeline)
+    }
+
+    /// Gives the next slot to one of the `ready` pipelines, none if no
+    /// pipeline with a weight is ready.
+    pub fn pick(&mut self, ready: &[Pipeline]) -> Option<Pipeline> {
+        let mut competing: Vec<&mut (Pipeline, u32, i64)> = self
+            .shares
+            .iter_mut()
+            .filter(|(pipeline, weight, _)| *weight > 0 && ready.contains(pipeline))
+            .collect();
+        let total: i64 = competing.iter().map(|share| share.1 as i64).sum();
+        for share in competing.iter_mut() {
+            share.2 += share.1 as i64;
+        }
+        let best = competing
+            .into_iter()
+            .reduce(|best, share| if share.2 > best.2 { share } else { best })?;
+        best.2 -= total;
+        Some(best.0)
+    }
+}
+
+/// Runs the pipelines of `allocator` side by side, one double fetch started
+/// every `pacing.time_between_requests` seconds whichever pipeline gets it,
+/// so the request rate stays that of a single pipeline. A pipeline with
+/// nothing due or a fetch still running gives its slot to the others; one
+/// that fails is left out for the rest of the run and its error returned
+/// once the others are done. Stops when `budget` is spent, on shutdown, or
+/// when no queue has anything due. Returns how many fetches were made.
+pub async fn run_concurrently(
+    client: &Client,
+    mut allocator: QuotaAllocator,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<u32, Box<dyn Error>> {
+    info!(
+        "Running {:?} concurrently within {:?}.",
+        allocator.pipelines().collect::<Vec<_>>(),
+        budget
+    );
+    let mut in_flight = FuturesUnordered::new();
+    let mut busy: Vec<Pipeline> = Vec::new();
+    let mut failed: Vec<Pipeline> = Vec::new();
+    let mut first_error: Option<Box<dyn Error>> = None;
+    let mut used = 0;
+    let mut next_slot = Instant::now();
+    let mut scheduling = true;
+    while scheduling || !in_flight.is_empty() {
+        let finished = tokio::select! {
+            Some(finished) = in_flight.next(), if !in_flight.is_empty() => Some(finished),
+            _ = shutdown.sleep_until(next_slot), if scheduling => None,
+        };
+        if let Some((pipeline, result)) = finished {
+            busy.retain(|busy| *busy != pipeline);
+            if let Err(e) = result {
+                error!("{:?} pipeline failed, leaving it out: {}", pipeline, e);
+                failed.push(pipeline);
+                first_error.get_or_insert(e);
+            }
+            continue;
+        }
+        if !budget.allows(used) || shutdown.is_requested() {
+            scheduling = false;
+            continue;
+        }
+        next_slot = Instant::now() + config::time_between_requests();
+        let mut ready = Vec::new();
+        for pipeline in allocator.pipelines() {
+            if busy.contains(&pipeline) || failed.contains(&pipeline) {
+                continue;
+            }
+            if ft_mongodb_app_queue_stats::count_due(client, pipeline.queue_name()).await? > 0 {
+                ready.push(pipeline);
+            }
+        }
+        let Some(pipeline) = allocator.pick(&ready) else {
+            scheduling = !busy.is_empty();
+            continue;
+        };
+        busy.push(pipeline);
+        used += 1;
+        in_flight.push(async move {
+            let result = fetch_once(client, pipeline, token_1, token_2).await;
+            (pipeline, result)
+        });
+    }
+    info!("Concurrent run made {} fetches.", used);
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(used),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_follow_the_weights() {
+        let mut allocator =
+            QuotaAllocator::new(&[(Pipeline::Profiles, 2), (Pipeline::Locations, 1)]);
+        let ready = [Pipeline::Profiles, Pipeline::Locations];
+        let picks: Vec<Pipeline> = (0..6).filter_map(|_| allocator.pick(&ready)).collect();
+        assert_eq!(
+            picks,
+            [
+                Pipeline::Profiles,
+                Pipeline::Locations,
+                Pipeline::Profiles,
+                Pipeline::Profiles,
+                Pipeline::Locations,
+                Pipeline::Profiles,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_idle_pipelines_leave_their_share() {
+        let mut allocator = QuotaAllocator::new(&[(Pipeline::Profiles, 1), (Pipeline::Events, 3)]);
+        for _ in 0..4 {
+            assert_eq!(
+                allocator.pick(&[Pipeline::Profiles]),
+                Some(Pipeline::Profiles)
+            );
+        }
+        assert_eq!(
+            allocator.pick(&[Pipeline::Profiles, Pipeline::Events]),
+            Some(Pipeline::Events)
+        );
+        assert_eq!(allocator.pick(&[Pipeline::Locations]), None);
+        assert_eq!(allocator.pick(&[]), None);
+    }
+}
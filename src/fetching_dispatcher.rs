@@ -0,0 +1,160 @@
+use std::error::Error;
+
+use log::{info, warn};
+use mongodb::Client;
+use oauth2::AccessToken;
+
+use crate::fetching_pipeline::run_until_drained;
+use crate::ft_mongodb_app_new_profile_index;
+use crate::ft_mongodb_app_seeder::{SeedDedupe, SeedTarget, seed_queue};
+use crate::ft_mongodb_dry_run;
+use crate::ft_mongodb_mode;
+use crate::pipeline_config::{Advance, PipelineConfig, StageConfig};
+use crate::run_budget::RunBudget;
+use crate::shutdown::Shutdown;
+
+/// Runs the stage of the current mode, advancing to the next stage of
+/// `config` whenever the stage is done, until `budget` is spent. Does
+/// nothing while the mode is paused.
+/// Stops after a full cycle without a single fetch so empty queues do not
+/// spin forever. Returns how many fetches were made.
+pub async fn run_dispatcher(
+    client: &Client,
+    config: &PipelineConfig,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<u32, Box<dyn Error>> {
+    let mut used = 0;
+    let mut idle_stages = 0;
+    let state = ft_mongodb_mode::get_mode_state(client).await?;
+    if state.paused {
+        info!(
+            "Mode {} is paused, nothing dispatched.",
+            state.mode.as_str()
+        );
+        return Ok(0);
+    }
+    let mut mode = state.mode;
+    if config.stage(mode).is_none() {
+        warn!(
+            "Mode {} is not part of the pipeline, restarting it.",
+            mode.as_str()
+        );
+        mode = config.first_mode();
+        ft_mongodb_mode::force_mode(client, mode, "not a stage of the pipeline").await?;
+    }
+    while budget.allows(used) && idle_stages < config.stages.len() && !shutdown.is_requested() {
+        let stage = config.stage(mode).expect("current mode is a stage");
+        info!("Dispatching {} mode.", mode.as_str());
+        let stage_budget = budget.after(used);
+        let (stage_used, done) =
+            run_stage(client, stage, token_1, token_2, &stage_budget, shutdown).await?;
+        used += stage_used;
+        idle_stages = if stage_used == 0 { idle_stages + 1 } else { 0 };
+        if !done {
+            break;
+        }
+        mode = ft_mongodb_mode::advance_mode_from(client, config, mode).await?;
+    }
+    info!(
+        "Dispatcher made {} fetches, mode is {}.",
+        used,
+        mode.as_str()
+    );
+    Ok(used)
+}
+
+/// Returns how many fetches the stage used and whether it is done.
+async fn run_stage(
+    client: &Client,
+    stage: &StageConfig,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<(u32, bool), Box<dyn Error>> {
+    let Some(pipeline) = stage.resource else {
+        if stage.reserve_profiles {
+            let count = budget.estimated_fetches().saturating_mul(2);
+            ft_mongodb_app_new_profile_index::reserve_profile_ids_in_mongo(client, count).await?;
+        }
+        seed_all(client, &stage.seed).await?;
+        return Ok((0, true));
+    };
+    let budget = budget.capped(stage.budget);
+    let (token_1, token_2) = stage.credentials.pick(token_1, token_2);
+    let (used, drained) =
+        run_until_drained(client, pipeline, token_1, token_2, &budget, shutdown).await?;
+    let (then_seed, done) = stage_outcome(stage, used, drained);
+    seed_all(client, then_seed).await?;
+    Ok((used, done))
+}
+
+/// Queues to seed once a fetching stage used `used` fetches and drained its
+/// queue or not, and whether the stage is done.
+fn stage_outcome(stage: &StageConfig, used: u32, drained: bool) -> (&[SeedTarget], bool) {
+    let then_seed = match drained {
+        true => stage.then_seed.as_slice(),
+        false => &[],
+    };
+    let done = match stage.advance {
+        Advance::Drained => drained,
+        Advance::BudgetSpent => drained || Some(used) == stage.budget,
+        Advance::Always => true,
+    };
+    (then_seed, done)
+}
+
+async fn seed_all(client: &Client, targets: &[SeedTarget]) -> Result<(), Box<dyn Error>> {
+    for target in targets {
+        seed_queue(
+            client,
+            *target,
+            SeedDedupe::KeepExisting,
+            ft_mongodb_dry_run::is_enabled(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(toml: &str) -> StageConfig {
+        let config = PipelineConfig::parse(&format!(
+            "[[stages]]\nmode = \"events\"\nresource = \"events\"\n{}",
+            toml
+        ))
+        .unwrap();
+        config.stages[0].clone()
+    }
+
+    #[test]
+    fn test_drained_stage_is_done_only_once_its_queue_is_empty() {
+        let stage = stage("budget = 10\nthen_seed = [\"locations\"]");
+        assert_eq!(stage_outcome(&stage, 10, false), (&[][..], false));
+        assert_eq!(
+            stage_outcome(&stage, 3, true),
+            (&[SeedTarget::Locations][..], true)
+        );
+    }
+
+    #[test]
+    fn test_budget_spent_stage_is_done_when_drained_or_out_of_budget() {
+        let stage = stage("budget = 10\nadvance = \"budget_spent\"");
+        assert_eq!(stage_outcome(&stage, 9, false), (&[][..], false));
+        assert_eq!(stage_outcome(&stage, 10, false), (&[][..], true));
+        assert_eq!(stage_outcome(&stage, 2, true), (&[][..], true));
+    }
+
+    #[test]
+    fn test_always_stage_is_done_after_every_run() {
+        let stage = stage("advance = \"always\"");
+        assert_eq!(stage_outcome(&stage, 0, false), (&[][..], true));
+        assert_eq!(stage_outcome(&stage, 5, true), (&[][..], true));
+    }
+}
@@ -4,42 +4,59 @@ use mongodb::Client;
 use oauth2::AccessToken;
 
 use crate::ft_api;
-use crate::ft_mongodb_app_indexor::get_an_id;
-use crate::ft_mongodb_app_indexor::insert_id;
+use crate::ft_mongodb_app_indexor::{EventId, IndexOutcome, WorkQueue};
+use crate::ft_mongodb_app_runs;
+use crate::ft_mongodb_collections;
 use crate::ft_mongodb_events;
 
-const COLLECTION_NAME: &str = "events_ids";
+const COLLECTION_NAME: &str = ft_mongodb_collections::EVENT_QUEUE;
 
 pub async fn double_fetch_event_from_42_to_mongo(
     client: &Client,
     token1: &AccessToken,
     token2: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    futures::future::try_join(
-        fetch_event_from_42_to_mongo(client, token1),
-        fetch_event_from_42_to_mongo(client, token2),
-    )
-    .await?;
-    Ok(())
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let queue = WorkQueue::<EventId>::new(client, COLLECTION_NAME);
+    let claimed = queue.claim(2).await?;
+    if claimed.items.is_empty() {
+        info!("Nothing left to claim in {}.", queue.name());
+        return Ok(false);
+    }
+    let fetches = claimed
+        .items
+        .iter()
+        .zip([token1, token2])
+        .map(|(entry, token)| {
+            queue.for_item(entry.item.0, async move {
+                let outcome = fetch_event(client, token, entry.item.0).await?;
+                Ok::<_, Box<dyn std::error::Error>>((entry.item.0, outcome))
+            })
+        });
+    let outcomes = futures::future::try_join_all(fetches).await?;
+    queue
+        .settle_after_flush(claimed.lease_token, outcomes)
+        .await?;
+    Ok(true)
 }
 
-pub async fn fetch_event_from_42_to_mongo(
+async fn fetch_event(
     client: &Client,
     token: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let event_id = get_an_id(client, COLLECTION_NAME).await?;
-    let event_node = ft_api::request_event(&token, &event_id).await;
+    event_id: i64,
+) -> Result<IndexOutcome, Box<dyn std::error::Error>> {
+    let event_node = ft_api::request_event(token, &event_id).await;
     if event_node.is_err() {
         error!("Event recuperation failed for event_id: {}", event_id);
-        insert_id(client, event_id, COLLECTION_NAME).await?;
-        return Ok(());
+        ft_mongodb_app_runs::record_failed(1);
+        return Ok(IndexOutcome::Retry);
     }
     let event_node = event_node?;
     if event_node.is_null() {
         info!("All event is get for event_id: {}", event_id);
-        return Ok(());
+        return Ok(IndexOutcome::Done);
     }
+    ft_mongodb_app_runs::record_fetched(1);
     ft_mongodb_events::insert_event_in_mongodb(client, event_id, &event_node).await?;
     info!("Insertion succed in MongoDB for event: {}", event_id);
-    Ok(())
+    Ok(IndexOutcome::Done)
 }
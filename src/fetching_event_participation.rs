@@ -4,45 +4,65 @@ use mongodb::Client;
 use oauth2::AccessToken;
 
 use crate::ft_api;
-use crate::ft_mongodb_app_indexor::{
-    get_an_user_id_and_page_number, insert_user_id_and_page_number,
-};
+use crate::ft_mongodb_app_indexor::{IndexOutcome, UserPage, WorkQueue};
+use crate::ft_mongodb_app_runs;
+use crate::ft_mongodb_collections;
 use crate::ft_mongodb_events_participation;
 
-const COLLECTION_NAME: &str = "events_participation_index";
+const COLLECTION_NAME: &str = ft_mongodb_collections::PARTICIPATION_QUEUE;
 
 pub async fn double_fetch_events_participation_from_42_to_mongo(
     client: &Client,
     token1: &AccessToken,
     token2: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    futures::future::try_join(
-        fetch_events_participation_from_42_to_mongo(client, token1),
-        fetch_events_participation_from_42_to_mongo(client, token2),
-    )
-    .await?;
-    Ok(())
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let queue = WorkQueue::<UserPage>::new(client, COLLECTION_NAME);
+    let claimed = queue.claim(2).await?;
+    if claimed.items.is_empty() {
+        info!("Nothing left to claim in {}.", queue.name());
+        return Ok(false);
+    }
+    let fetches = claimed
+        .items
+        .iter()
+        .zip([token1, token2])
+        .map(|(entry, token)| {
+            queue.for_item(entry.item.user_id, async move {
+                let outcome = fetch_events_participation_page(client, token, &entry.item).await?;
+                Ok::<_, Box<dyn std::error::Error>>((entry.item.user_id, outcome))
+            })
+        });
+    let outcomes = futures::future::try_join_all(fetches).await?;
+    queue
+        .settle_after_flush(claimed.lease_token, outcomes)
+        .await?;
+    Ok(true)
 }
 
-pub async fn fetch_events_participation_from_42_to_mongo(
+async fn fetch_events_participation_page(
     client: &Client,
     token: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (user_id, page_number) = get_an_user_id_and_page_number(client, COLLECTION_NAME).await?;
-    let event_node = ft_api::request_event_participations(&token, &user_id, &page_number).await;
+    page: &UserPage,
+) -> Result<IndexOutcome, Box<dyn std::error::Error>> {
+    let UserPage {
+        user_id,
+        page_number,
+    } = *page;
+    let event_node = ft_api::request_event_participations(token, &user_id, &page_number).await;
     if event_node.is_err() {
         error!(
             "Events recuperation failed for user_id: {} page_number : {}",
             user_id, page_number
         );
-        insert_user_id_and_page_number(client, user_id, page_number, COLLECTION_NAME).await?;
-        return Ok(());
+        ft_mongodb_app_runs::record_failed(1);
+        return Ok(IndexOutcome::Retry);
     }
     let event_node = event_node?;
     if event_node.as_array().is_none() || event_node.as_array().unwrap().is_empty() {
         info!("All events is get for user_id: {}", user_id);
-        return Ok(());
+        return Ok(IndexOutcome::Done);
     }
+    ft_mongodb_app_runs::record_fetched(event_node.as_array().unwrap().len() as u64);
     let nb_insert = ft_mongodb_events_participation::insert_user_events_in_mongodb(
         client,
         user_id,
@@ -58,8 +78,7 @@ pub async fn fetch_events_participation_from_42_to_mongo(
             "All events is get for user_id: {} event index was not reinserted",
             user_id
         );
-        return Ok(());
+        return Ok(IndexOutcome::Done);
     }
-    insert_user_id_and_page_number(client, user_id, page_number + 1, COLLECTION_NAME).await?;
-    Ok(())
+    Ok(IndexOutcome::NextPage)
 }
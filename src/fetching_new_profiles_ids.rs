@@ -1,4 +1,3 @@
-
 use log::{info, warn};
 use mongodb::Client;
 use oauth2::AccessToken;
@@ -14,7 +13,7 @@ pub async fn fetch_profils_ids_from_42_to_mongo(
 ) -> Result<i32, Box<dyn std::error::Error>> {
     info!("Fetching profiles IDs from 42 API");
     let updated_at = "2024-11-06T12:04:39.139Z";
-    let profiles_ids = ft_api::request_profiles_ids(&token, page_number, updated_at).await;
+    let profiles_ids = ft_api::request_profiles_ids(token, page_number, updated_at).await;
     if profiles_ids.is_err() {
         warn!("Failed to fetch profiles IDs");
         return Err(profiles_ids.err().unwrap());
@@ -0,0 +1,118 @@
+use clap::ValueEnum;
+use log::{debug, info};
+use mongodb::Client;
+use oauth2::AccessToken;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{
+    config, fetching_event, fetching_event_participation, fetching_locations, fetching_profile,
+    ft_mongodb_app_queue_stats, ft_mongodb_collections, run_budget::RunBudget, shutdown::Shutdown,
+};
+
+/// A resource fetched from its work queue, two requests at a time.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pipeline {
+    Profiles,
+    Locations,
+    Events,
+    Participations,
+}
+
+impl Pipeline {
+    /// Work queue the pipeline claims its ids from.
+    pub fn queue_name(&self) -> &str {
+        match self {
+            Pipeline::Profiles => ft_mongodb_collections::PROFILE_QUEUE,
+            Pipeline::Locations => ft_mongodb_collections::LOCATION_QUEUE,
+            Pipeline::Events => ft_mongodb_collections::EVENT_QUEUE,
+            Pipeline::Participations => ft_mongodb_collections::PARTICIPATION_QUEUE,
+        }
+    }
+}
+
+/// One double fetch of `pipeline`, one request per token. Returns `false`
+/// when nothing could be claimed, like when another worker claimed the last
+/// due items first.
+pub async fn fetch_once(
+    client: &Client,
+    pipeline: Pipeline,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match pipeline {
+        Pipeline::Profiles => {
+            fetching_profile::double_fetch_profiles_from_42_to_mongo(client, token_1, token_2).await
+        }
+        Pipeline::Locations => {
+            fetching_locations::double_fetch_location_from_42_to_mongo(client, token_1, token_2)
+                .await
+        }
+        Pipeline::Events => {
+            fetching_event::double_fetch_event_from_42_to_mongo(client, token_1, token_2).await
+        }
+        Pipeline::Participations => {
+            fetching_event_participation::double_fetch_events_participation_from_42_to_mongo(
+                client, token_1, token_2,
+            )
+            .await
+        }
+    }
+}
+
+/// Runs double fetches of `pipeline` while `budget` allows, starting one
+/// `pacing.time_between_requests` seconds, until its queue has nothing left
+/// to claim. A shutdown lets the running fetch finish and skips the rest.
+/// Returns how many fetches were made.
+pub async fn run_pipeline(
+    client: &Client,
+    pipeline: Pipeline,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    info!("Running {:?} pipeline within {:?}.", pipeline, budget);
+    let mut done = 0;
+    while budget.allows(done) && !shutdown.is_requested() {
+        let next = Instant::now() + config::time_between_requests();
+        if !fetch_once(client, pipeline, token_1, token_2).await? {
+            break;
+        }
+        done += 1;
+        debug!("Waiting until {:?} before next fetch.", next);
+        shutdown.sleep_until(next).await;
+    }
+    info!("{:?} pipeline made {} fetches.", pipeline, done);
+    Ok(done)
+}
+
+/// Like `run_pipeline`, but stops early once nothing in the queue is due.
+/// Returns how many fetches were made and whether the queue is now empty,
+/// leased and delayed items included.
+pub async fn run_until_drained(
+    client: &Client,
+    pipeline: Pipeline,
+    token_1: &AccessToken,
+    token_2: &AccessToken,
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<(u32, bool), Box<dyn std::error::Error>> {
+    info!("Draining {:?} pipeline within {:?}.", pipeline, budget);
+    let mut done = 0;
+    while budget.allows(done) && !shutdown.is_requested() {
+        if ft_mongodb_app_queue_stats::count_due(client, pipeline.queue_name()).await? == 0 {
+            let stats =
+                ft_mongodb_app_queue_stats::get_queue_stats(client, pipeline.queue_name()).await?;
+            return Ok((done, stats.depth + stats.in_flight == 0));
+        }
+        let next = Instant::now() + config::time_between_requests();
+        if fetch_once(client, pipeline, token_1, token_2).await? {
+            done += 1;
+        }
+        debug!("Waiting until {:?} before next fetch.", next);
+        shutdown.sleep_until(next).await;
+    }
+    Ok((done, false))
+}
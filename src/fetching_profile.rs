@@ -1,25 +1,27 @@
-use std::time::Duration;
-
 use log::{info, warn};
 use mongodb::Client;
 use oauth2::AccessToken;
-use tokio::time::{Instant, sleep_until};
 
 use crate::{
-    fetching_locations, ft_api, ft_mongodb_app_new_profile_index, ft_mongodb_profiles::{self, insert_failed_id_in_mongo, insert_ignoring_id_in_mongo}, NB_FETCH
+    fetching_pipeline::{Pipeline, run_pipeline},
+    ft_api,
+    ft_mongodb_app_indexor::IndexOutcome,
+    ft_mongodb_app_new_profile_index, ft_mongodb_app_runs,
+    ft_mongodb_profiles::{self, insert_failed_id_in_mongo, insert_ignoring_id_in_mongo},
+    run_budget::RunBudget,
+    shutdown::Shutdown,
 };
 
-pub const TIME_BETWEEN_REQUESTS: u32 = 3;
-
 pub async fn fetch_profil_from_42_to_mongo(
     client: &Client,
     user_id: u32,
     token: &AccessToken,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Fetching profile from 42 API for user_id: {}", user_id);
-    let profile_node = ft_api::request_profil(&token, &user_id).await;
+    let profile_node = ft_api::request_profil(token, &user_id).await;
     if profile_node.is_err() {
         warn!("Profil failed for user_id: {}", user_id);
+        ft_mongodb_app_runs::record_failed(1);
         insert_failed_id_in_mongo(client, user_id).await?;
         return Ok(());
     }
@@ -29,66 +31,61 @@ pub async fn fetch_profil_from_42_to_mongo(
         insert_ignoring_id_in_mongo(client, user_id).await?;
         return Ok(());
     }
+    ft_mongodb_app_runs::record_fetched(1);
     ft_mongodb_profiles::insert_profile_in_mongo(client, &profile_node, user_id).await?;
     info!("Profile inserted in MongoDB for user_id: {}", user_id);
     Ok(())
 }
 
-pub async fn fetch_locations_from_42_to_mongo(
-    client: &Client,
-    token_1: &AccessToken,
-    token_2: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("All users have been fetched.");
-    for _i in 0..NB_FETCH {
-        let current = Instant::now();
-        futures::future::try_join(
-            fetching_locations::fetch_location_from_42_to_mongo(&client, &token_1),
-            fetching_locations::fetch_location_from_42_to_mongo(&client, &token_2),
-        )
-        .await?;
-        sleep_until(current + Duration::from_secs(TIME_BETWEEN_REQUESTS.into())).await;
-    }
-    Ok(())
-}
-
-pub async fn fetch_profiles_from_42_to_mongodb(
+pub async fn double_fetch_profiles_from_42_to_mongo(
     client: &Client,
-    user_id: u32,
     api_key_1: &AccessToken,
     api_key_2: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut i = user_id;
-    while i < user_id + (NB_FETCH * 2) {
-        let current = Instant::now();
-        futures::future::try_join(
-            fetch_profil_from_42_to_mongo(&client, i.clone(), &api_key_2),
-            fetch_profil_from_42_to_mongo(&client, i.clone() + 1, &api_key_1),
-        )
-        .await?;
-        i += 2;
-        sleep_until(current + Duration::from_secs(TIME_BETWEEN_REQUESTS.into())).await;
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let queue = ft_mongodb_app_new_profile_index::profile_queue(client);
+    let claimed = queue.claim(2).await?;
+    if claimed.items.is_empty() {
+        info!("Nothing left to claim in {}.", queue.name());
+        return Ok(false);
     }
-    Ok(())
+    let fetches = claimed
+        .items
+        .iter()
+        .zip([api_key_2, api_key_1])
+        .map(|(entry, token)| {
+            let fetch = fetch_profil_from_42_to_mongo(client, entry.item.0 as u32, token);
+            queue.for_item(entry.item.0, fetch)
+        });
+    futures::future::try_join_all(fetches).await?;
+    // Failed profiles are kept in failed_id, so every claimed id is done.
+    let outcomes = claimed
+        .items
+        .iter()
+        .map(|entry| (entry.item.0, IndexOutcome::Done))
+        .collect();
+    queue
+        .settle_after_flush(claimed.lease_token, outcomes)
+        .await?;
+    Ok(true)
 }
 
-pub async fn fetch_and_update_profiles_from_42_to_mongodb(
+/// Scans the next range of user ids from the sequential counter.
+pub async fn fetch_profiles_from_42_to_mongodb(
     client: &Client,
     api_key_1: &AccessToken,
     api_key_2: &AccessToken,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut i = 1;
-    while i < 1 + (NB_FETCH * 2) {
-        let current = Instant::now();
-        let id1 = ft_mongodb_app_new_profile_index::get_a_profile_id(client).await?;
-        let id2 = ft_mongodb_app_new_profile_index::get_a_profile_id(client).await?;
-        futures::future::try_join(
-            fetch_profil_from_42_to_mongo(&client, id1 as u32, &api_key_2),
-            fetch_profil_from_42_to_mongo(&client, id2 as u32, &api_key_1),
-        )
-        .await?;
-        i += 2;
-        sleep_until(current + Duration::from_secs(TIME_BETWEEN_REQUESTS.into())).await;
-    }
-    Ok(())
+    budget: &RunBudget,
+    shutdown: &Shutdown,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let count = budget.estimated_fetches().saturating_mul(2);
+    ft_mongodb_app_new_profile_index::reserve_profile_ids_in_mongo(client, count).await?;
+    run_pipeline(
+        client,
+        Pipeline::Profiles,
+        api_key_1,
+        api_key_2,
+        budget,
+        shutdown,
+    )
+    .await
 }
@@ -5,6 +5,8 @@ use oauth2::{
 use reqwest::header::AUTHORIZATION;
 use std::error::Error;
 
+use crate::ft_mongodb_app_runs;
+
 const TOKEN_URL: &str = "https://api.intra.42.fr/oauth/token";
 const AUTH_URL: &str = "https://api.intra.42.fr/oauth/authorize";
 const API_URL: &str = "https://api.intra.42.fr/v2";
@@ -128,9 +130,7 @@ pub async fn request_profiles_ids(
     );
     let url = format!(
         "{}/users?page[size]=100&page[number]={}&sort=id&range[updated_at]={},2050-11-06T12:04:39.139Z",
-        API_URL,
-        page_number,
-        updated_at
+        API_URL, page_number, updated_at
     );
     let response = send_http_request(&url, token).await?;
     if response.status() != 404 && response.status() != 200 {
@@ -194,7 +194,9 @@ async fn send_http_request(
         .await
         .map_err(|e| {
             error!("HTTP request failed for url {}: {}", url, e);
+            ft_mongodb_app_runs::record_request(None);
             e
         })?;
+    ft_mongodb_app_runs::record_request(Some(response.status().as_u16()));
     Ok(response)
 }
@@ -1,18 +1,24 @@
+use std::error::Error;
+
 use log::{error, info};
 use mongodb::{Client, bson::doc, options::ClientOptions};
 
-pub async fn connect_to_mongodb(mongodb_uri: &str) -> Client {
-    info!("Connecting to MongoDB at {}", mongodb_uri);
-    let client_options = ClientOptions::parse(mongodb_uri).await.unwrap();
-    let client = Client::with_options(client_options).unwrap();
+use crate::config;
+
+pub async fn connect_to_mongodb(mongodb_uri: &str) -> Result<Client, Box<dyn Error>> {
+    info!("Connecting to MongoDB.");
+    let client_options = ClientOptions::parse(mongodb_uri)
+        .await
+        .map_err(|e| format!("Invalid MongoDB URI: {}", e))?;
+    let client = Client::with_options(client_options)?;
     client
-        .database("42")
+        .database(config::data_database_name())
         .run_command(doc! { "ping": 1 })
         .await
-        .unwrap_or_else(|e| {
+        .map_err(|e| {
             error!("Failed to connect to MongoDB: {}", e);
-            panic!();
-        });
+            e
+        })?;
     info!("Successfully connected to MongoDB.");
-    client
+    Ok(client)
 }
@@ -1,139 +1,679 @@
-use std::error::Error;
+use std::{error::Error, future::Future, marker::PhantomData, time::Duration};
 
+use futures::TryStreamExt;
 use log::{debug, error, info};
 use mongodb::{
     Client, Collection,
-    bson::{Bson, Document, doc},
+    bson::{Bson, DateTime, Document, doc, oid::ObjectId},
 };
 
-const DATABASE_NAME: &str = "application";
+use crate::config::{app_collection, collection_name};
+use crate::ft_mongodb_app_queue_stats::{self, QueueStats};
+use crate::ft_mongodb_app_runs;
+use crate::ft_mongodb_app_workers::worker_id;
+use crate::ft_mongodb_dry_run;
+use crate::ft_mongodb_write_buffer;
 
-pub async fn get_an_id(client: &Client, collection_name: &str) -> Result<i64, Box<dyn Error>> {
-    let doc = find_one_and_delete(client, collection_name).await?;
-    let user_id = match doc.get("_id") {
-        Some(Bson::Int64(id)) => *id,
-        Some(Bson::Int32(id)) => *id as i64,
-        _ => {
-            return Err("Field '_id' does not have the expected type".into());
+pub const DEFAULT_PRIORITY: i32 = 0;
+/// How long claimed items stay invisible to other workers before they are
+/// handed out again, e.g. when a run dies before acknowledging them.
+pub const LEASE_DURATION: Duration = Duration::from_secs(15 * 60);
+/// Failed attempts after which a claimed index is parked in the dead letters.
+pub const MAX_ATTEMPTS: i32 = 5;
+
+/// Scheduling hints stored next to every queued index.
+///
+/// Dequeue hands out the highest `priority` first and skips items whose
+/// `not_before` is still in the future. Documents written before priorities
+/// existed have neither field and are served after every prioritised item.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct QueuePriority {
+    pub priority: i32,
+    pub not_before: Option<DateTime>,
+}
+
+impl QueuePriority {
+    pub fn new(priority: i32) -> Self {
+        QueuePriority {
+            priority,
+            not_before: None,
         }
-    };
-    info!("Current index is for user {}", user_id);
-    return Ok(user_id);
-}
-
-pub async fn insert_id(
-    client: &Client,
-    user_id: i64,
-    collection_name: &str,
-) -> Result<(), Box<dyn Error>> {
-    let collection: Collection<Document> =
-        client.database(DATABASE_NAME).collection(collection_name);
-    collection
-        .replace_one(doc! {"_id": user_id}, doc! {"_id": user_id})
-        .upsert(true)
-        .await
-        .or_else(|e| {
-            error!(
-                "Failed to insert index for user {} in MongoDB: {}",
-                user_id, e
-            );
-            Err(e)
-        })?;
-    info!("Index for user {} inserted in MongoDB.", user_id);
-    Ok(())
-}
-
-pub async fn get_an_user_id_and_page_number(
-    client: &Client,
-    collection_name: &str,
-) -> Result<(i64, i32), Box<dyn Error>> {
-    let result = find_one_and_delete(client, collection_name).await?;
-    let (user_id, page_number) = match parse_user_page_doc(result) {
-        Ok(value) => value,
-        Err(value) => return value,
-    };
-    info!(
-        "Current index is for user {} and page {}",
-        user_id, page_number
-    );
-    return Ok((user_id, page_number));
-}
-
-pub async fn insert_user_id_and_page_number(
-    client: &Client,
-    user_id: i64,
-    page_number: i32,
-    collection_name: &str,
-) -> Result<(), Box<dyn Error>> {
-    let collection: Collection<Document> =
-        client.database(DATABASE_NAME).collection(collection_name);
-    collection
-        .replace_one(
-            doc! {"_id": user_id},
-            doc! {"_id": user_id, "page_number": page_number},
-        )
-        .upsert(true)
-        .await
-        .or_else(|e| {
+    }
+
+    pub fn not_before(mut self, not_before: DateTime) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    fn write_into(&self, doc: &mut Document) {
+        doc.insert("priority", self.priority);
+        if let Some(not_before) = self.not_before {
+            doc.insert("not_before", not_before);
+        }
+    }
+}
+
+/// Something that can be stored as one document of a work queue.
+pub trait QueueItem: Sized {
+    /// Value of the `_id` of the queue document.
+    fn id(&self) -> i64;
+    /// Queue document without its scheduling fields.
+    fn to_document(&self) -> Document;
+    fn from_document(doc: &Document) -> Result<Self, Box<dyn Error>>;
+}
+
+/// A 42 event still to be fetched, as queued in `events_ids`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventId(pub i64);
+
+/// A 42 user whose profile is still to be fetched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileId(pub i64);
+
+/// The next page of a paginated per-user resource, as queued in
+/// `location_index` or `events_participation_index`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserPage {
+    pub user_id: i64,
+    pub page_number: i32,
+}
+
+impl UserPage {
+    pub fn first_page(user_id: i64) -> Self {
+        UserPage {
+            user_id,
+            page_number: 1,
+        }
+    }
+
+    pub fn next_page(&self) -> Self {
+        UserPage {
+            user_id: self.user_id,
+            page_number: self.page_number + 1,
+        }
+    }
+}
+
+impl QueueItem for EventId {
+    fn id(&self) -> i64 {
+        self.0
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {"_id": self.0}
+    }
+
+    fn from_document(doc: &Document) -> Result<Self, Box<dyn Error>> {
+        Ok(EventId(parse_id(doc)?))
+    }
+}
+
+impl QueueItem for ProfileId {
+    fn id(&self) -> i64 {
+        self.0
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {"_id": self.0}
+    }
+
+    fn from_document(doc: &Document) -> Result<Self, Box<dyn Error>> {
+        Ok(ProfileId(parse_id(doc)?))
+    }
+}
+
+impl QueueItem for UserPage {
+    fn id(&self) -> i64 {
+        self.user_id
+    }
+
+    fn to_document(&self) -> Document {
+        doc! {"_id": self.user_id, "page_number": self.page_number}
+    }
+
+    fn from_document(doc: &Document) -> Result<Self, Box<dyn Error>> {
+        let (user_id, page_number) = parse_user_page_doc(doc)?;
+        Ok(UserPage {
+            user_id,
+            page_number,
+        })
+    }
+}
+
+/// A dequeued item together with the priority it was queued with, so that
+/// requeues keep prioritised users at their rank.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueEntry<T> {
+    pub item: T,
+    pub priority: QueuePriority,
+}
+
+/// Items claimed in one call, hidden from other workers until they are
+/// acknowledged, released or requeued with the same `lease_token`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimedIndexes<T> {
+    pub lease_token: ObjectId,
+    pub items: Vec<QueueEntry<T>>,
+}
+
+/// What to do with a claimed index once its request is done.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexOutcome {
+    /// Everything was fetched, the index leaves the queue.
+    Done,
+    /// The request failed, the same index is put back as it was.
+    Retry,
+    /// A full page was fetched, the index is put back on the next page.
+    NextPage,
+}
+
+/// A collection of the `application` database used as a work queue of `T`.
+///
+/// Every queue shares the same document layout: the item fields from
+/// `QueueItem::to_document`, the `QueuePriority` fields, and while claimed
+/// `lease_token`, `leased_until` and `attempts`.
+#[derive(Debug, Clone)]
+pub struct WorkQueue<T> {
+    client: Client,
+    name: String,
+    item: PhantomData<fn() -> T>,
+}
+
+impl<T: QueueItem> WorkQueue<T> {
+    pub fn new(client: &Client, collection_name: &str) -> Self {
+        WorkQueue {
+            client: client.clone(),
+            name: collection_name.to_string(),
+            item: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        app_collection(&self.client, &self.name)
+    }
+
+    /// Queues `item`, replacing the payload and schedule of whatever was
+    /// queued under the same id. A lease on it is kept, so its holder can
+    /// still settle it.
+    pub async fn push(&self, item: &T, priority: &QueuePriority) -> Result<(), Box<dyn Error>> {
+        let mut index = item.to_document();
+        priority.write_into(&mut index);
+        if ft_mongodb_dry_run::dumps(&self.collection(), [&index]) {
+            return Ok(());
+        }
+        index.remove("_id");
+        let mut update = doc! {"$set": index};
+        if priority.not_before.is_none() {
+            update.insert("$unset", doc! {"not_before": ""});
+        }
+        self.collection()
+            .update_one(doc! {"_id": item.id()}, update)
+            .upsert(true)
+            .await
+            .map_err(|e| {
+                error!(
+                    "Failed to insert index {} in {}: {}",
+                    item.id(),
+                    self.name,
+                    e
+                );
+                e
+            })?;
+        info!("Index {} inserted in {}.", item.id(), self.name);
+        Ok(())
+    }
+
+    /// Queues new items in one call. Fails if one of them is already queued,
+    /// the others are inserted anyway.
+    pub async fn push_many(
+        &self,
+        items: &[T],
+        priority: &QueuePriority,
+    ) -> Result<usize, Box<dyn Error>> {
+        if items.is_empty() {
+            return Ok(0);
+        }
+        let documents: Vec<Document> = items
+            .iter()
+            .map(|item| {
+                let mut index = item.to_document();
+                priority.write_into(&mut index);
+                index
+            })
+            .collect();
+        if ft_mongodb_dry_run::dumps(&self.collection(), &documents) {
+            return Ok(items.len());
+        }
+        self.collection()
+            .insert_many(documents)
+            .ordered(false)
+            .await
+            .map_err(|e| {
+                error!("Failed to insert indexes in {}: {}", self.name, e);
+                e
+            })?;
+        info!("Inserted {} indexes in {}.", items.len(), self.name);
+        Ok(items.len())
+    }
+
+    /// Claims up to `count` due items: pick candidates, stamp them with a
+    /// fresh lease token, then read back what this token actually won. Items
+    /// grabbed concurrently by another worker are left out of the batch.
+    /// In dry-run mode the items are only read: they stay due and unleased,
+    /// and the next claims of this process skip them.
+    pub async fn claim(&self, count: usize) -> Result<ClaimedIndexes<T>, Box<dyn Error>> {
+        if ft_mongodb_dry_run::is_enabled() {
+            let filter = claimable_filter(&self.collection(), DateTime::now());
+            let peeked = self.read_due(filter, count).await?;
+            let namespace = self.collection().namespace().to_string();
+            let ids = peeked.items.iter().map(|entry| entry.item.id());
+            ft_mongodb_dry_run::hand_out(&namespace, ids);
+            return Ok(peeked);
+        }
+        let collection = self.collection();
+        let now = DateTime::now();
+        let lease_token = ObjectId::new();
+        let candidates: Vec<Document> = collection
+            .find(due_filter(now))
+            .sort(priority_sort())
+            .limit(count as i64)
+            .projection(doc! {"_id": 1})
+            .await?
+            .try_collect()
+            .await
+            .map_err(|e| {
+                error!(
+                    "Failed to find indexes in {} from MongoDB: {}",
+                    self.name, e
+                );
+                e
+            })?;
+        if candidates.is_empty() {
+            return Ok(ClaimedIndexes {
+                lease_token,
+                items: vec![],
+            });
+        }
+        let ids: Vec<Bson> = candidates
+            .into_iter()
+            .filter_map(|mut doc| doc.remove("_id"))
+            .collect();
+        let mut filter = due_filter(now);
+        filter.insert("_id", doc! {"$in": ids});
+        collection
+            .update_many(
+                filter,
+                doc! {"$set": {
+                    "lease_token": lease_token,
+                    "leased_until": now.saturating_add_duration(LEASE_DURATION),
+                    "leased_by": worker_id(),
+                }},
+            )
+            .await?;
+        let claimed: Vec<Document> = collection
+            .find(doc! {"lease_token": lease_token})
+            .sort(priority_sort())
+            .await?
+            .try_collect()
+            .await?;
+        let items = claimed
+            .iter()
+            .map(|doc| {
+                Ok(QueueEntry {
+                    item: T::from_document(doc)?,
+                    priority: parse_priority(doc)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        info!("Claimed {} indexes from {}.", items.len(), self.name);
+        Ok(ClaimedIndexes { lease_token, items })
+    }
+
+    /// The next `count` due items, as `claim` would pick them, without
+    /// leasing them.
+    pub async fn peek(&self, count: usize) -> Result<ClaimedIndexes<T>, Box<dyn Error>> {
+        self.read_due(due_filter(DateTime::now()), count).await
+    }
+
+    /// The first `count` items matching `filter`, in dequeue order.
+    async fn read_due(
+        &self,
+        filter: Document,
+        count: usize,
+    ) -> Result<ClaimedIndexes<T>, Box<dyn Error>> {
+        let peeked: Vec<Document> = self
+            .collection()
+            .find(filter)
+            .sort(priority_sort())
+            .limit(count as i64)
+            .await?
+            .try_collect()
+            .await?;
+        let items = peeked
+            .iter()
+            .map(|doc| {
+                Ok(QueueEntry {
+                    item: T::from_document(doc)?,
+                    priority: parse_priority(doc)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        info!("Peeked at {} indexes of {}.", items.len(), self.name);
+        Ok(ClaimedIndexes {
+            lease_token: ObjectId::new(),
+            items,
+        })
+    }
+
+    /// Removes claimed indexes from the queue once they have been fetched.
+    pub async fn acknowledge(
+        &self,
+        lease_token: &ObjectId,
+        ids: &[i64],
+    ) -> Result<u64, Box<dyn Error>> {
+        if ids.is_empty() || ft_mongodb_dry_run::skips(&self.collection(), "acknowledge") {
+            return Ok(0);
+        }
+        let result = self
+            .collection()
+            .delete_many(doc! {"_id": {"$in": ids}, "lease_token": lease_token})
+            .await
+            .map_err(|e| {
+                error!("Failed to acknowledge indexes in {}: {}", self.name, e);
+                e
+            })?;
+        debug!(
+            "{} indexes acknowledged in {}.",
+            result.deleted_count, self.name
+        );
+        self.record_completed(result.deleted_count).await?;
+        Ok(result.deleted_count)
+    }
+
+    /// Puts claimed indexes back untouched so they can be retried. Indexes
+    /// that already failed `MAX_ATTEMPTS` times go to the dead letters instead.
+    pub async fn release(
+        &self,
+        lease_token: &ObjectId,
+        ids: &[i64],
+    ) -> Result<u64, Box<dyn Error>> {
+        if ft_mongodb_dry_run::skips(&self.collection(), "release") {
+            return Ok(0);
+        }
+        let count_attempt = vec![doc! {
+            "$set": {"attempts": {"$add": [{"$ifNull": ["$attempts", 0]}, 1]}}
+        }];
+        self.update_leased(lease_token, ids, count_attempt).await?;
+        self.move_exhausted_to_dead_letters(lease_token, ids)
+            .await?;
+        let update = vec![doc! {"$unset": ["lease_token", "leased_until", "leased_by"]}];
+        let released = self.update_leased(lease_token, ids, update).await?;
+        ft_mongodb_app_runs::record_requeued(released);
+        Ok(released)
+    }
+
+    /// Puts claimed user indexes back on their following page.
+    pub async fn requeue_next_pages(
+        &self,
+        lease_token: &ObjectId,
+        ids: &[i64],
+    ) -> Result<u64, Box<dyn Error>> {
+        if ft_mongodb_dry_run::skips(&self.collection(), "requeue of next pages") {
+            return Ok(0);
+        }
+        let update = vec![
+            doc! {"$set": {"page_number": {"$add": ["$page_number", 1]}}},
+            doc! {"$unset": ["lease_token", "leased_until", "leased_by"]},
+        ];
+        self.update_leased(lease_token, ids, update).await
+    }
+
+    /// Applies every outcome of a claimed batch with at most one call per kind.
+    pub async fn settle(
+        &self,
+        lease_token: &ObjectId,
+        outcomes: &[(i64, IndexOutcome)],
+    ) -> Result<(), Box<dyn Error>> {
+        let ids_with = |outcome: IndexOutcome| -> Vec<i64> {
+            outcomes
+                .iter()
+                .filter(|(_, o)| *o == outcome)
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        self.acknowledge(lease_token, &ids_with(IndexOutcome::Done))
+            .await?;
+        self.release(lease_token, &ids_with(IndexOutcome::Retry))
+            .await?;
+        self.requeue_next_pages(lease_token, &ids_with(IndexOutcome::NextPage))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs `fetch` with the data it buffers marked as fetched for the item
+    /// `id`, see `ft_mongodb_write_buffer::for_item`.
+    pub async fn for_item<F: Future>(&self, id: i64, fetch: F) -> F::Output {
+        ft_mongodb_write_buffer::for_item(&self.name, id, fetch).await
+    }
+
+    /// Settles like `settle`, once the documents fetched for these outcomes
+    /// are written, see `ft_mongodb_write_buffer::after_flush`. Items the
+    /// server refused a write for are settled as `Retry` instead.
+    pub async fn settle_after_flush(
+        &self,
+        lease_token: ObjectId,
+        outcomes: Vec<(i64, IndexOutcome)>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        T: Clone + 'static,
+    {
+        let queue = self.clone();
+        let ids = outcomes.iter().map(|(id, _)| *id).collect();
+        let settle: ft_mongodb_write_buffer::Settle = Box::new(move |refused| {
+            Box::pin(async move {
+                let outcomes: Vec<(i64, IndexOutcome)> = outcomes
+                    .into_iter()
+                    .map(|(id, outcome)| match refused.contains(&id) {
+                        true => (id, IndexOutcome::Retry),
+                        false => (id, outcome),
+                    })
+                    .collect();
+                queue.settle(&lease_token, &outcomes).await
+            })
+        });
+        ft_mongodb_write_buffer::after_flush(&self.name, ids, settle).await
+    }
+
+    /// Changes the priority of items already waiting in the queue, keeping
+    /// the rest of their state (such as `page_number`) untouched.
+    pub async fn prioritize(
+        &self,
+        ids: &[i64],
+        priority: &QueuePriority,
+    ) -> Result<u64, Box<dyn Error>> {
+        if ft_mongodb_dry_run::skips(&self.collection(), "prioritize") {
+            return Ok(0);
+        }
+        let update = match priority.not_before {
+            Some(not_before) => doc! {
+                "$set": {"priority": priority.priority, "not_before": not_before}
+            },
+            None => doc! {
+                "$set": {"priority": priority.priority},
+                "$unset": {"not_before": ""}
+            },
+        };
+        let result = self
+            .collection()
+            .update_many(doc! {"_id": {"$in": ids}}, update)
+            .await
+            .map_err(|e| {
+                error!("Failed to prioritize indexes in {}: {}", self.name, e);
+                e
+            })?;
+        info!(
+            "{} indexes of {} set to priority {}.",
+            result.modified_count, self.name, priority.priority
+        );
+        Ok(result.modified_count)
+    }
+
+    /// Counts acknowledged items for the throughput statistics.
+    async fn record_completed(&self, count: u64) -> Result<(), Box<dyn Error>> {
+        ft_mongodb_app_queue_stats::record_completed(&self.client, &self.name, count).await
+    }
+
+    pub async fn stats(&self) -> Result<QueueStats, Box<dyn Error>> {
+        ft_mongodb_app_queue_stats::get_queue_stats(&self.client, &self.name).await
+    }
+
+    async fn move_exhausted_to_dead_letters(
+        &self,
+        lease_token: &ObjectId,
+        ids: &[i64],
+    ) -> Result<(), Box<dyn Error>> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let collection = self.collection();
+        let exhausted = doc! {
+            "_id": {"$in": ids},
+            "lease_token": lease_token,
+            "attempts": {"$gte": MAX_ATTEMPTS},
+        };
+        collection
+            .aggregate(vec![
+                doc! {"$match": exhausted.clone()},
+                doc! {"$set": {"dead_lettered_at": DateTime::now()}},
+                doc! {"$unset": ["lease_token", "leased_until", "leased_by"]},
+                doc! {"$merge": {
+                    "into": collection_name(&dead_letters_name(&self.name)),
+                    "whenMatched": "replace",
+                    "whenNotMatched": "insert",
+                }},
+            ])
+            .await?;
+        let result = collection.delete_many(exhausted).await?;
+        if result.deleted_count > 0 {
             error!(
-                "Failed to insert index for user {} in MongoDB: {}",
-                user_id, e
+                "{} indexes of {} moved to dead letters after {} attempts.",
+                result.deleted_count, self.name, MAX_ATTEMPTS
             );
-            Err(e)
-        })?;
-    info!(
-        "Index for user {} and page {} inserted in MongoDB.",
-        user_id, page_number
-    );
-    Ok(())
-}
-
-async fn find_one_and_delete(
-    client: &Client,
-    collection_name: &str,
-) -> Result<Document, Box<dyn Error>> {
-    let collection: Collection<Document> =
-        client.database(DATABASE_NAME).collection(collection_name);
-    let result = collection.find_one_and_delete(doc! {}).await.or_else(|e| {
-        error!("Maybe all index have been fetched.");
-        error!(
-            "Failed to find a index in {} from MongoDB: {}",
-            collection_name, e
+        }
+        Ok(())
+    }
+
+    async fn update_leased(
+        &self,
+        lease_token: &ObjectId,
+        ids: &[i64],
+        update: Vec<Document>,
+    ) -> Result<u64, Box<dyn Error>> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let result = self
+            .collection()
+            .update_many(
+                doc! {"_id": {"$in": ids}, "lease_token": lease_token},
+                update,
+            )
+            .await
+            .map_err(|e| {
+                error!("Failed to requeue indexes in {}: {}", self.name, e);
+                e
+            })?;
+        debug!(
+            "{} indexes requeued in {}.",
+            result.modified_count, self.name
         );
-        Err(e)
-    })?;
-    if let Some(doc) = result {
-        Ok(doc)
-    } else {
-        Err("Failed to fetch current index from MongoDB.".into())
+        Ok(result.modified_count)
     }
 }
 
-fn parse_user_page_doc(doc: Document) -> Result<(i64, i32), Result<(i64, i32), Box<dyn Error>>> {
-    debug!("Found a index {:?} in MongoDB.", doc);
-    let user_id = match doc.get("_id") {
-        Some(Bson::Int64(id)) => *id,
-        Some(Bson::Int32(id)) => *id as i64,
-        _ => return Err(Err("Field '_id' does not have the expected type".into())),
-    };
+/// Collection holding the indexes of `collection_name` that kept failing.
+pub fn dead_letters_name(collection_name: &str) -> String {
+    format!("{}_dead_letters", collection_name)
+}
+
+/// Matches queued items that may be handed out right now.
+pub fn due_filter(now: DateTime) -> Document {
+    doc! {
+        "$and": [
+            {"$or": [{"not_before": null}, {"not_before": {"$lte": now}}]},
+            {"$or": [{"leased_until": null}, {"leased_until": {"$lte": now}}]},
+        ]
+    }
+}
+
+/// `due_filter` without, in dry-run mode, the items this process already
+/// handed out: they are only read, so they stay due.
+pub fn claimable_filter(queue: &Collection<Document>, now: DateTime) -> Document {
+    let mut filter = due_filter(now);
+    if ft_mongodb_dry_run::is_enabled() {
+        let handed_out = ft_mongodb_dry_run::handed_out(&queue.namespace().to_string());
+        filter.insert("_id", doc! {"$nin": handed_out});
+    }
+    filter
+}
+
+/// Highest priority first, then by `_id` so the order is stable.
+pub fn priority_sort() -> Document {
+    doc! {"priority": -1, "_id": 1}
+}
+
+fn parse_id(doc: &Document) -> Result<i64, Box<dyn Error>> {
+    match doc.get("_id") {
+        Some(Bson::Int64(id)) => Ok(*id),
+        Some(Bson::Int32(id)) => Ok(*id as i64),
+        _ => Err("Field '_id' does not have the expected type".into()),
+    }
+}
+
+fn parse_user_page_doc(doc: &Document) -> Result<(i64, i32), Box<dyn Error>> {
+    let user_id = parse_id(doc)?;
     let page_number = match doc.get("page_number") {
         Some(Bson::Int32(page)) => *page,
         Some(Bson::Int64(page)) => *page as i32,
-        _ => {
-            return Err(Err(
-                "Field 'page_number' does not have the expected type".into()
-            ));
-        }
+        _ => return Err("Field 'page_number' does not have the expected type".into()),
     };
     Ok((user_id, page_number))
 }
 
+/// Items queued before priorities existed have neither `priority` nor
+/// `not_before`, and get the default ones.
+fn parse_priority(doc: &Document) -> Result<QueuePriority, Box<dyn Error>> {
+    let priority = match doc.get("priority") {
+        None => DEFAULT_PRIORITY,
+        Some(Bson::Int32(priority)) => *priority,
+        Some(Bson::Int64(priority)) => i32::try_from(*priority)
+            .map_err(|_| format!("Field 'priority' {} is out of range", priority))?,
+        Some(_) => return Err("Field 'priority' does not have the expected type".into()),
+    };
+    let not_before = match doc.get("not_before") {
+        None | Some(Bson::Null) => None,
+        Some(Bson::DateTime(not_before)) => Some(*not_before),
+        Some(_) => return Err("Field 'not_before' does not have the expected type".into()),
+    };
+    Ok(QueuePriority {
+        priority,
+        not_before,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ft_mongodb_collections;
+    use crate::ft_mongodb_write_buffer::WriteBuffer;
     use mongodb::bson::doc;
     use mongodb::{Client, options::ClientOptions};
     use std::error::Error;
+    use std::sync::Arc;
     use testcontainers::{
         ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
     };
@@ -178,15 +718,105 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_an_user_id_and_page_number() -> Result<(), Box<dyn Error>> {
+    async fn test_claim_user_page() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let queue = WorkQueue::<UserPage>::new(&client, "locations_index");
+        let page = UserPage {
+            user_id: 42,
+            page_number: 1,
+        };
+        let priority = QueuePriority::default();
+        queue.push(&page, &priority).await?;
+        let claimed = queue.claim(1).await?;
+        assert_eq!(claimed.items.len(), 1);
+        assert_eq!(claimed.items[0].item, page);
+        assert_eq!(claimed.items[0].priority, priority);
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_claim_follows_priority_and_not_before() -> Result<(), Box<dyn Error>> {
         let (client, container) = get_test_mongo_client().await;
-        let user_id = 42;
-        let page_number = 1;
-        insert_user_id_and_page_number(&client, user_id, page_number, "locations_index").await?;
-        let (found_user_id, found_page_number) =
-            get_an_user_id_and_page_number(&client, "locations_index").await?;
-        assert_eq!(found_user_id, user_id);
-        assert_eq!(found_page_number, page_number);
+        let queue = WorkQueue::<EventId>::new(&client, ft_mongodb_collections::EVENT_QUEUE);
+        let later = DateTime::now().saturating_add_duration(Duration::from_secs(3600));
+        queue.push(&EventId(1), &QueuePriority::default()).await?;
+        queue.push(&EventId(2), &QueuePriority::new(10)).await?;
+        queue
+            .push(&EventId(3), &QueuePriority::new(20).not_before(later))
+            .await?;
+        let claimed = queue.claim(3).await?;
+        let ids: Vec<EventId> = claimed.items.iter().map(|entry| entry.item).collect();
+        assert_eq!(ids, vec![EventId(2), EventId(1)]);
+        assert!(queue.claim(1).await?.items.is_empty());
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_push_keeps_the_lease_of_a_claimed_item() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let queue = WorkQueue::<EventId>::new(&client, ft_mongodb_collections::EVENT_QUEUE);
+        queue.push(&EventId(1), &QueuePriority::default()).await?;
+        let claimed = queue.claim(1).await?;
+        queue.push(&EventId(1), &QueuePriority::new(5)).await?;
+        assert!(queue.claim(1).await?.items.is_empty());
+        queue
+            .settle(&claimed.lease_token, &[(1, IndexOutcome::Done)])
+            .await?;
+        assert_eq!(queue.peek(1).await?.items.len(), 0);
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_claim_and_settle_user_pages() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let queue = WorkQueue::<UserPage>::new(&client, ft_mongodb_collections::LOCATION_QUEUE);
+        let priority = QueuePriority::default();
+        for user_id in 1..=3 {
+            let page = UserPage {
+                user_id,
+                page_number: 1,
+            };
+            queue.push(&page, &priority).await?;
+        }
+        let claimed = queue.claim(2).await?;
+        assert_eq!(claimed.items.len(), 2);
+        let again = queue.claim(2).await?;
+        assert_eq!(again.items.len(), 1);
+        let outcomes = [
+            (claimed.items[0].item.user_id, IndexOutcome::Done),
+            (claimed.items[1].item.user_id, IndexOutcome::NextPage),
+        ];
+        queue.settle(&claimed.lease_token, &outcomes).await?;
+        let next = queue.claim(2).await?;
+        assert_eq!(next.items.len(), 1);
+        assert_eq!(next.items[0].item.user_id, claimed.items[1].item.user_id);
+        assert_eq!(next.items[0].item.page_number, 2);
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_settle_after_flush_of_an_empty_page_runs_at_once() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let queue = WorkQueue::<UserPage>::new(&client, ft_mongodb_collections::LOCATION_QUEUE);
+        let page = UserPage {
+            user_id: 42,
+            page_number: 3,
+        };
+        queue.push(&page, &QueuePriority::default()).await?;
+        let claimed = queue.claim(1).await?;
+        let buffer = Arc::new(WriteBuffer::new(&client));
+        let outcomes = vec![(page.user_id, IndexOutcome::Done)];
+        ft_mongodb_write_buffer::scope(Arc::clone(&buffer), async {
+            queue
+                .settle_after_flush(claimed.lease_token, outcomes)
+                .await
+        })
+        .await?;
+        assert_eq!(queue.collection().count_documents(doc! {}).await?, 0);
         container.stop().await?;
         Ok(())
     }
@@ -194,14 +824,50 @@ mod tests {
     #[test]
     fn test_parse_location_index_with_i32() {
         use super::*;
-        let doc = doc! { "_id": 42 as i32, "page_number": 1 };
-        assert_eq!(parse_user_page_doc(doc).unwrap(), (42, 1));
+        let doc = doc! { "_id": 42_i32, "page_number": 1 };
+        assert_eq!(parse_user_page_doc(&doc).unwrap(), (42, 1));
     }
 
     #[test]
     fn test_parse_location_index_with_i64() {
         use super::*;
-        let doc = doc! { "_id": 42 as i64, "page_number": 1 };
-        assert_eq!(parse_user_page_doc(doc).unwrap(), (42, 1));
+        let doc = doc! { "_id": 42_i64, "page_number": 1 };
+        assert_eq!(parse_user_page_doc(&doc).unwrap(), (42, 1));
+    }
+
+    #[test]
+    fn test_user_page_round_trips_through_document() {
+        let page = UserPage {
+            user_id: 7,
+            page_number: 3,
+        };
+        assert_eq!(UserPage::from_document(&page.to_document()).unwrap(), page);
+    }
+
+    #[test]
+    fn test_parse_priority_defaults_when_missing() {
+        let doc = doc! { "_id": 42_i64, "page_number": 1 };
+        assert_eq!(parse_priority(&doc).unwrap(), QueuePriority::default());
+    }
+
+    #[test]
+    fn test_parse_priority_with_not_before() {
+        let not_before = DateTime::from_millis(1_700_000_000_000);
+        let doc = doc! { "_id": 42_i64, "priority": 5, "not_before": not_before };
+        assert_eq!(
+            parse_priority(&doc).unwrap(),
+            QueuePriority::new(5).not_before(not_before)
+        );
+    }
+
+    #[test]
+    fn test_parse_priority_rejects_wrong_types_and_overflows() {
+        let doc = doc! { "_id": 42_i64, "priority": "high" };
+        assert!(parse_priority(&doc).is_err());
+        let doc = doc! { "_id": 42_i64, "priority": i64::MAX };
+        let error = parse_priority(&doc).unwrap_err();
+        assert!(error.to_string().contains(&i64::MAX.to_string()));
+        let doc = doc! { "_id": 42_i64, "not_before": 5 };
+        assert!(parse_priority(&doc).is_err());
     }
 }
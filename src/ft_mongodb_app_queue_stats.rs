@@ -0,0 +1,193 @@
+use std::{error::Error, time::Duration};
+
+use futures::TryStreamExt;
+use log::{debug, error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{Bson, DateTime, Document, doc},
+};
+
+use crate::config::app_collection;
+use crate::ft_mongodb_app_indexor::{claimable_filter, dead_letters_name};
+use crate::ft_mongodb_collections::{self, QUEUES};
+use crate::ft_mongodb_dry_run;
+
+const THROUGHPUT_COLLECTION_NAME: &str = ft_mongodb_collections::QUEUE_THROUGHPUT;
+const BUCKET_MILLIS: i64 = 60 * 60 * 1000;
+/// Window used to compute the recent drain rate of a queue.
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueStats {
+    pub queue: String,
+    /// Items waiting to be claimed, due or not.
+    pub depth: u64,
+    /// Items currently claimed under a lease that has not expired.
+    pub in_flight: u64,
+    pub dead_letters: u64,
+    /// Items completed during the last `THROUGHPUT_WINDOW`.
+    pub completed_recently: u64,
+    pub throughput_per_hour: f64,
+    /// Time left to drain `depth + in_flight` at the recent rate, if known.
+    pub eta: Option<Duration>,
+}
+
+/// Adds `count` completed items to the current hourly bucket of `queue_name`.
+/// Buckets are kept in `application.queue_throughput` so rates survive runs.
+pub async fn record_completed(
+    client: &Client,
+    queue_name: &str,
+    count: u64,
+) -> Result<(), Box<dyn Error>> {
+    if count == 0 {
+        return Ok(());
+    }
+    let bucket = bucket_start(DateTime::now());
+    let collection: Collection<Document> = app_collection(client, THROUGHPUT_COLLECTION_NAME);
+    if ft_mongodb_dry_run::skips(&collection, "recording completions") {
+        return Ok(());
+    }
+    collection
+        .update_one(
+            doc! {"_id": {"queue": queue_name, "bucket": bucket}},
+            doc! {
+                "$inc": {"completed": count as i64},
+                "$setOnInsert": {"queue": queue_name, "bucket": bucket},
+            },
+        )
+        .upsert(true)
+        .await
+        .map_err(|e| {
+            error!("Failed to record throughput of {}: {}", queue_name, e);
+            e
+        })?;
+    debug!("Recorded {} completed items for {}.", count, queue_name);
+    Ok(())
+}
+
+pub async fn get_queue_stats(
+    client: &Client,
+    queue_name: &str,
+) -> Result<QueueStats, Box<dyn Error>> {
+    let queue: Collection<Document> = app_collection(client, queue_name);
+    let now = DateTime::now();
+    let in_flight = queue
+        .count_documents(doc! {"leased_until": {"$gt": now}})
+        .await?;
+    let total = queue.count_documents(doc! {}).await?;
+    let dead_letters = app_collection::<Document>(client, &dead_letters_name(queue_name))
+        .count_documents(doc! {})
+        .await?;
+    let completed_recently = get_completed_since(
+        client,
+        queue_name,
+        DateTime::from_millis(now.timestamp_millis() - THROUGHPUT_WINDOW.as_millis() as i64),
+    )
+    .await?;
+    let stats = build_queue_stats(
+        queue_name,
+        total.saturating_sub(in_flight),
+        in_flight,
+        dead_letters,
+        completed_recently,
+    );
+    info!(
+        "{}: depth {}, in flight {}, dead letters {}, {:.1}/h",
+        stats.queue, stats.depth, stats.in_flight, stats.dead_letters, stats.throughput_per_hour
+    );
+    Ok(stats)
+}
+
+/// Items of `queue_name` that a claim would hand out right now.
+pub async fn count_due(client: &Client, queue_name: &str) -> Result<u64, Box<dyn Error>> {
+    let queue: Collection<Document> = app_collection(client, queue_name);
+    let filter = claimable_filter(&queue, DateTime::now());
+    Ok(queue.count_documents(filter).await?)
+}
+
+pub async fn get_all_queue_stats(client: &Client) -> Result<Vec<QueueStats>, Box<dyn Error>> {
+    let mut all_stats = Vec::with_capacity(QUEUES.len());
+    for queue_name in QUEUES {
+        all_stats.push(get_queue_stats(client, queue_name).await?);
+    }
+    Ok(all_stats)
+}
+
+async fn get_completed_since(
+    client: &Client,
+    queue_name: &str,
+    since: DateTime,
+) -> Result<u64, Box<dyn Error>> {
+    let collection: Collection<Document> = app_collection(client, THROUGHPUT_COLLECTION_NAME);
+    let totals: Vec<Document> = collection
+        .aggregate(vec![
+            doc! {"$match": {"queue": queue_name, "bucket": {"$gte": bucket_start(since)}}},
+            doc! {"$group": {"_id": null, "completed": {"$sum": "$completed"}}},
+        ])
+        .await?
+        .try_collect()
+        .await?;
+    let completed = match totals.first().and_then(|doc| doc.get("completed")) {
+        Some(Bson::Int32(completed)) => *completed as u64,
+        Some(Bson::Int64(completed)) => *completed as u64,
+        _ => 0,
+    };
+    Ok(completed)
+}
+
+fn build_queue_stats(
+    queue_name: &str,
+    depth: u64,
+    in_flight: u64,
+    dead_letters: u64,
+    completed_recently: u64,
+) -> QueueStats {
+    let window_hours = THROUGHPUT_WINDOW.as_secs_f64() / 3600.0;
+    let throughput_per_hour = completed_recently as f64 / window_hours;
+    let eta = if throughput_per_hour > 0.0 {
+        let remaining = (depth + in_flight) as f64;
+        Some(Duration::from_secs_f64(
+            remaining / throughput_per_hour * 3600.0,
+        ))
+    } else {
+        None
+    };
+    QueueStats {
+        queue: queue_name.to_string(),
+        depth,
+        in_flight,
+        dead_letters,
+        completed_recently,
+        throughput_per_hour,
+        eta,
+    }
+}
+
+fn bucket_start(date: DateTime) -> DateTime {
+    let millis = date.timestamp_millis();
+    DateTime::from_millis(millis - millis.rem_euclid(BUCKET_MILLIS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_start_truncates_to_the_hour() {
+        let date = DateTime::from_millis(3 * BUCKET_MILLIS + 1234);
+        assert_eq!(bucket_start(date), DateTime::from_millis(3 * BUCKET_MILLIS));
+    }
+
+    #[test]
+    fn test_build_queue_stats_estimates_eta_from_throughput() {
+        let stats = build_queue_stats(ft_mongodb_collections::EVENT_QUEUE, 90, 6, 1, 48);
+        assert_eq!(stats.throughput_per_hour, 2.0);
+        assert_eq!(stats.eta, Some(Duration::from_secs(48 * 3600)));
+    }
+
+    #[test]
+    fn test_build_queue_stats_without_throughput_has_no_eta() {
+        let stats = build_queue_stats(ft_mongodb_collections::EVENT_QUEUE, 90, 0, 0, 0);
+        assert_eq!(stats.eta, None);
+    }
+}
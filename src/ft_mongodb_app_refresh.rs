@@ -0,0 +1,230 @@
+use std::{error::Error, time::Duration};
+
+use log::{error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{DateTime, Document, doc},
+};
+
+use crate::config::{app_database_name, collection_name, data_collection};
+use crate::ft_mongodb_app_indexor::DEFAULT_PRIORITY;
+use crate::ft_mongodb_app_seeder::count_candidates;
+use crate::ft_mongodb_collections;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Stored resources that can be put back in their queue once stale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshResource {
+    Profiles,
+    Locations,
+    EventsParticipation,
+    Events,
+}
+
+/// Which stored documents a refresh policy applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshScope {
+    All,
+    /// Only users whose stored profile has `active?` set.
+    ActiveUsers,
+}
+
+/// Re-enqueue `resource` once its `fetched_at` is older than `every`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefreshPolicy {
+    pub resource: RefreshResource,
+    pub every: Duration,
+    pub scope: RefreshScope,
+    pub priority: i32,
+}
+
+pub const DEFAULT_REFRESH_POLICIES: [RefreshPolicy; 3] = [
+    RefreshPolicy {
+        resource: RefreshResource::Profiles,
+        every: Duration::from_secs(7 * 24 * 60 * 60),
+        scope: RefreshScope::All,
+        priority: DEFAULT_PRIORITY,
+    },
+    RefreshPolicy {
+        resource: RefreshResource::Locations,
+        every: DAY,
+        scope: RefreshScope::ActiveUsers,
+        priority: DEFAULT_PRIORITY + 1,
+    },
+    RefreshPolicy {
+        resource: RefreshResource::EventsParticipation,
+        every: Duration::from_secs(7 * 24 * 60 * 60),
+        scope: RefreshScope::ActiveUsers,
+        priority: DEFAULT_PRIORITY,
+    },
+];
+
+impl RefreshResource {
+    pub fn queue_name(&self) -> &str {
+        match self {
+            RefreshResource::Profiles => ft_mongodb_collections::PROFILE_QUEUE,
+            RefreshResource::Locations => ft_mongodb_collections::LOCATION_QUEUE,
+            RefreshResource::EventsParticipation => ft_mongodb_collections::PARTICIPATION_QUEUE,
+            RefreshResource::Events => ft_mongodb_collections::EVENT_QUEUE,
+        }
+    }
+
+    fn stored_collection(&self) -> &str {
+        match self {
+            RefreshResource::Profiles => ft_mongodb_collections::PROFILES,
+            RefreshResource::Locations => ft_mongodb_collections::LOCATIONS,
+            RefreshResource::EventsParticipation => ft_mongodb_collections::EVENT_PARTICIPATIONS,
+            RefreshResource::Events => ft_mongodb_collections::EVENTS,
+        }
+    }
+
+    fn is_paginated(&self) -> bool {
+        matches!(
+            self,
+            RefreshResource::Locations | RefreshResource::EventsParticipation
+        )
+    }
+}
+
+impl RefreshPolicy {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.scope == RefreshScope::ActiveUsers && self.resource == RefreshResource::Events {
+            return Err(
+                "Events are not tied to a user, they cannot be scoped to active users".into(),
+            );
+        }
+        if self.every.is_zero() {
+            return Err("A refresh policy needs a non-zero period".into());
+        }
+        Ok(())
+    }
+}
+
+/// Re-enqueues every stored document `policy` considers stale into the queue
+/// of its resource, keeping items that are already queued untouched.
+/// Returns how many ids were stale; with `dry_run` nothing is written.
+pub async fn sweep_stale(
+    client: &Client,
+    policy: &RefreshPolicy,
+    dry_run: bool,
+) -> Result<u64, Box<dyn Error>> {
+    policy.validate()?;
+    let collection: Collection<Document> =
+        data_collection(client, policy.resource.stored_collection());
+    let mut pipeline = build_refresh_pipeline(policy, DateTime::now());
+    let stale = count_candidates(&collection, pipeline.clone()).await?;
+    if dry_run || stale == 0 {
+        info!("{} stale ids for {}.", stale, policy.resource.queue_name());
+        return Ok(stale);
+    }
+    pipeline.push(doc! {"$merge": {
+        "into": {
+            "db": app_database_name(),
+            "coll": collection_name(policy.resource.queue_name()),
+        },
+        "whenMatched": "keepExisting",
+        "whenNotMatched": "insert",
+    }});
+    collection.aggregate(pipeline).await.map_err(|e| {
+        error!(
+            "Failed to re-enqueue stale ids into {}: {}",
+            policy.resource.queue_name(),
+            e
+        );
+        e
+    })?;
+    info!(
+        "Re-enqueued {} stale ids into {}.",
+        stale,
+        policy.resource.queue_name()
+    );
+    Ok(stale)
+}
+
+pub async fn sweep_all_stale(
+    client: &Client,
+    policies: &[RefreshPolicy],
+    dry_run: bool,
+) -> Result<Vec<(RefreshPolicy, u64)>, Box<dyn Error>> {
+    let mut swept = Vec::with_capacity(policies.len());
+    for policy in policies {
+        swept.push((*policy, sweep_stale(client, policy, dry_run).await?));
+    }
+    Ok(swept)
+}
+
+fn build_refresh_pipeline(policy: &RefreshPolicy, now: DateTime) -> Vec<Document> {
+    let cutoff = DateTime::from_millis(now.timestamp_millis() - policy.every.as_millis() as i64);
+    let stale = doc! {"$match": {"$or": [
+        {"fetched_at": null},
+        {"fetched_at": {"$lt": cutoff}},
+    ]}};
+    let mut pipeline = Vec::new();
+    if policy.resource.is_paginated() {
+        // Locations and participations are stored one document per visit or
+        // event, staleness is per user. Documents without a user would make
+        // a `null` queue item no claim can parse.
+        pipeline.push(doc! {"$match": {"user_id": {"$ne": null}}});
+        pipeline.push(doc! {"$group": {"_id": "$user_id", "fetched_at": {"$max": "$fetched_at"}}});
+    }
+    pipeline.push(stale);
+    if policy.scope == RefreshScope::ActiveUsers {
+        pipeline.push(doc! {"$lookup": {
+            "from": collection_name(ft_mongodb_collections::PROFILES),
+            "localField": "_id",
+            "foreignField": "_id",
+            "as": "profile",
+            "pipeline": [{"$match": {"active?": true}}, {"$project": {"_id": 1}}],
+        }});
+        pipeline.push(doc! {"$match": {"profile": {"$ne": []}}});
+    }
+    pipeline.push(doc! {"$project": {"_id": 1}});
+    let mut fields = doc! {"priority": policy.priority};
+    if policy.resource.is_paginated() {
+        fields.insert("page_number", 1);
+    }
+    pipeline.push(doc! {"$addFields": fields});
+    pipeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_refresh_policies_are_valid() {
+        for policy in DEFAULT_REFRESH_POLICIES {
+            assert!(policy.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_events_cannot_be_scoped_to_active_users() {
+        let policy = RefreshPolicy {
+            resource: RefreshResource::Events,
+            every: DAY,
+            scope: RefreshScope::ActiveUsers,
+            priority: DEFAULT_PRIORITY,
+        };
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn test_refresh_pipeline_for_active_users_locations() {
+        let now = DateTime::from_millis(10 * DAY.as_millis() as i64);
+        let pipeline = build_refresh_pipeline(&DEFAULT_REFRESH_POLICIES[1], now);
+        assert_eq!(pipeline[0], doc! {"$match": {"user_id": {"$ne": null}}});
+        assert!(pipeline[1].contains_key("$group"));
+        let cutoff = DateTime::from_millis(9 * DAY.as_millis() as i64);
+        assert_eq!(
+            pipeline[2],
+            doc! {"$match": {"$or": [{"fetched_at": null}, {"fetched_at": {"$lt": cutoff}}]}}
+        );
+        assert!(pipeline[3].contains_key("$lookup"));
+        assert_eq!(
+            pipeline.last().unwrap(),
+            &doc! {"$addFields": {"priority": 1, "page_number": 1}}
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use log::{error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+};
+
+use crate::config::{AppConfig, app_collection};
+use crate::ft_mongodb_app_workers::worker_id;
+use crate::ft_mongodb_collections;
+use crate::ft_mongodb_dry_run;
+use crate::ft_mongodb_mode;
+
+const RUNS_COLLECTION_NAME: &str = ft_mongodb_collections::RUNS;
+/// Histogram key of requests that never got a response.
+pub const TRANSPORT_ERROR: &str = "transport_error";
+
+static COUNTERS: RunCounters = RunCounters::new();
+
+/// Everything this process did against the 42 API and MongoDB since it
+/// started. Journals keep the values seen at their start and record the
+/// difference, so daemon rounds each get their own share.
+struct RunCounters {
+    requests: AtomicU64,
+    fetched: AtomicU64,
+    inserted: AtomicU64,
+    requeued: AtomicU64,
+    failed: AtomicU64,
+    http_statuses: Mutex<BTreeMap<String, u64>>,
+}
+
+impl RunCounters {
+    const fn new() -> Self {
+        RunCounters {
+            requests: AtomicU64::new(0),
+            fetched: AtomicU64::new(0),
+            inserted: AtomicU64::new(0),
+            requeued: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            http_statuses: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// Counts one request sent to the 42 API, under its HTTP status or under
+/// `TRANSPORT_ERROR` when no response came back.
+pub fn record_request(status: Option<u16>) {
+    COUNTERS.requests.fetch_add(1, Ordering::Relaxed);
+    let key = status.map_or_else(|| TRANSPORT_ERROR.to_string(), |status| status.to_string());
+    let mut statuses = COUNTERS.http_statuses.lock().unwrap();
+    *statuses.entry(key).or_insert(0) += 1;
+}
+
+/// Documents received from the 42 API.
+pub fn record_fetched(count: u64) {
+    COUNTERS.fetched.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Documents written to the data database.
+pub fn record_inserted(count: u64) {
+    COUNTERS.inserted.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Queue items put back to be tried again.
+pub fn record_requeued(count: u64) {
+    COUNTERS.requeued.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Items whose fetch failed.
+pub fn record_failed(count: u64) {
+    COUNTERS.failed.fetch_add(count, Ordering::Relaxed);
+}
+
+/// A reading of the counters, or the difference between two readings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunCounts {
+    pub requests: u64,
+    pub fetched: u64,
+    pub inserted: u64,
+    pub requeued: u64,
+    pub failed: u64,
+    pub http_statuses: BTreeMap<String, u64>,
+}
+
+impl RunCounts {
+    pub fn current() -> Self {
+        RunCounts {
+            requests: COUNTERS.requests.load(Ordering::Relaxed),
+            fetched: COUNTERS.fetched.load(Ordering::Relaxed),
+            inserted: COUNTERS.inserted.load(Ordering::Relaxed),
+            requeued: COUNTERS.requeued.load(Ordering::Relaxed),
+            failed: COUNTERS.failed.load(Ordering::Relaxed),
+            http_statuses: COUNTERS.http_statuses.lock().unwrap().clone(),
+        }
+    }
+
+    /// What was counted after `earlier`.
+    pub fn since(&self, earlier: &RunCounts) -> RunCounts {
+        let http_statuses = self
+            .http_statuses
+            .iter()
+            .map(|(status, count)| {
+                let before = earlier.http_statuses.get(status).copied().unwrap_or(0);
+                (status.clone(), count - before)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        RunCounts {
+            requests: self.requests - earlier.requests,
+            fetched: self.fetched - earlier.fetched,
+            inserted: self.inserted - earlier.inserted,
+            requeued: self.requeued - earlier.requeued,
+            failed: self.failed - earlier.failed,
+            http_statuses,
+        }
+    }
+
+    fn to_document(&self) -> Document {
+        let http_statuses: Document = self
+            .http_statuses
+            .iter()
+            .map(|(status, count)| (status.clone(), (*count as i64).into()))
+            .collect();
+        doc! {
+            "requests": self.requests as i64,
+            "fetched": self.fetched as i64,
+            "inserted": self.inserted as i64,
+            "requeued": self.requeued as i64,
+            "failed": self.failed as i64,
+            "http_statuses": http_statuses,
+        }
+    }
+}
+
+/// One execution of a command, written to `application.runs` when it starts
+/// with a `running` status and completed when it ends. A run left `running`
+/// with no `ended_at` was killed before it could finish.
+pub struct RunJournal {
+    client: Client,
+    id: ObjectId,
+    started_at: DateTime,
+    counts_at_start: RunCounts,
+}
+
+impl RunJournal {
+    pub async fn start(
+        client: &Client,
+        command: &str,
+        config: &AppConfig,
+    ) -> Result<RunJournal, Box<dyn Error>> {
+        let journal = RunJournal {
+            client: client.clone(),
+            id: ObjectId::new(),
+            started_at: DateTime::now(),
+            counts_at_start: RunCounts::current(),
+        };
+        let mode = current_mode_name(client).await;
+        if ft_mongodb_dry_run::skips(&journal.collection(), "journaling") {
+            return Ok(journal);
+        }
+        journal
+            .collection()
+            .insert_one(doc! {
+                "_id": journal.id,
+                "command": command,
+                "worker_id": worker_id(),
+                "started_at": journal.started_at,
+                "status": "running",
+                "mode": mode,
+                "credentials": {
+                    "profil_id": config.credentials.profil_id.as_deref(),
+                    "location_id": config.credentials.location_id.as_deref(),
+                },
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to start run journal in MongoDB: {}", e);
+                e
+            })?;
+        info!("Run {} of {} started.", journal.id, command);
+        Ok(journal)
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        app_collection(&self.client, RUNS_COLLECTION_NAME)
+    }
+
+    /// Records the end time, the counts since `start` and, if `result` is an
+    /// error, the fatal error.
+    pub async fn finish<T>(self, result: &Result<T, Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        let ended_at = DateTime::now();
+        let counts = RunCounts::current().since(&self.counts_at_start);
+        let (status, fatal_error) = match result {
+            Ok(_) => ("succeeded", None),
+            Err(e) => ("failed", Some(e.to_string())),
+        };
+        let mut update = counts.to_document();
+        update.insert("ended_at", ended_at);
+        update.insert(
+            "duration_ms",
+            ended_at.timestamp_millis() - self.started_at.timestamp_millis(),
+        );
+        update.insert("status", status);
+        update.insert("end_mode", current_mode_name(&self.client).await);
+        update.insert("fatal_error", fatal_error);
+        if !ft_mongodb_dry_run::is_enabled() {
+            self.collection()
+                .update_one(doc! {"_id": self.id}, doc! {"$set": update})
+                .await
+                .map_err(|e| {
+                    error!("Failed to finish run journal {} in MongoDB: {}", self.id, e);
+                    e
+                })?;
+        }
+        info!(
+            "Run {} {}: {} requests, {} fetched, {} inserted, {} requeued, {} failed.",
+            self.id,
+            status,
+            counts.requests,
+            counts.fetched,
+            counts.inserted,
+            counts.requeued,
+            counts.failed
+        );
+        Ok(())
+    }
+}
+
+/// The stored mode, or none if it cannot be read, which must not keep the
+/// run from being journaled.
+async fn current_mode_name(client: &Client) -> Option<&'static str> {
+    ft_mongodb_mode::get_current_mode_from_mongo(client)
+        .await
+        .ok()
+        .map(|mode| mode.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::{Client, options::ClientOptions};
+    use testcontainers::{
+        ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
+    };
+
+    pub async fn get_test_mongo_client() -> (Client, ContainerAsync<GenericImage>) {
+        let container = match GenericImage::new("mongo", "latest")
+            .with_exposed_port(27017.tcp())
+            .start()
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Failed to start MongoDB container: {}", e);
+            }
+        };
+
+        let port = match container.get_host_port_ipv4(27017).await {
+            Ok(p) => p,
+            Err(e) => {
+                panic!("Failed to get MongoDB container port: {}", e);
+            }
+        };
+
+        let client_uri = format!("mongodb://localhost:{}/", port);
+        let options = ClientOptions::parse(&client_uri).await.unwrap();
+        let client = Client::with_options(options).unwrap();
+
+        let db = client.database("admin");
+        for _ in 0..10 {
+            match db.run_command(doc! {"ping": 1}).await {
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Waiting for MongoDB to be ready: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+
+        (client, container)
+    }
+
+    #[test]
+    fn test_counts_since_keep_only_the_difference() {
+        let earlier = RunCounts {
+            requests: 3,
+            fetched: 2,
+            http_statuses: BTreeMap::from([("200".to_string(), 2), ("429".to_string(), 1)]),
+            ..RunCounts::default()
+        };
+        let later = RunCounts {
+            requests: 5,
+            fetched: 4,
+            failed: 1,
+            http_statuses: BTreeMap::from([
+                ("200".to_string(), 3),
+                ("429".to_string(), 1),
+                (TRANSPORT_ERROR.to_string(), 1),
+            ]),
+            ..RunCounts::default()
+        };
+        let counts = later.since(&earlier);
+        assert_eq!(counts.requests, 2);
+        assert_eq!(counts.fetched, 2);
+        assert_eq!(counts.failed, 1);
+        assert_eq!(
+            counts.http_statuses,
+            BTreeMap::from([("200".to_string(), 1), (TRANSPORT_ERROR.to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_journal_records_counts_and_fatal_error() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+
+        let journal = RunJournal::start(&client, "fetch", &AppConfig::default()).await?;
+        record_request(Some(200));
+        record_request(None);
+        record_fetched(1);
+        let result: Result<(), Box<dyn Error>> = Err("token expired".into());
+        journal.finish(&result).await?;
+
+        let collection: Collection<Document> = app_collection(&client, RUNS_COLLECTION_NAME);
+        let run = collection.find_one(doc! {}).await?.unwrap();
+        assert_eq!(run.get_str("command")?, "fetch");
+        assert_eq!(run.get_str("status")?, "failed");
+        assert_eq!(run.get_str("fatal_error")?, "token expired");
+        assert!(run.get_i64("requests")? >= 2);
+        assert!(
+            run.get_document("http_statuses")?
+                .contains_key(TRANSPORT_ERROR)
+        );
+        assert!(run.get_datetime("ended_at").is_ok());
+        container.stop().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use std::error::Error;
+
+use clap::ValueEnum;
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{Bson, Document, doc},
+};
+use serde::Deserialize;
+
+use crate::config::{app_database_name, collection_name, data_collection};
+use crate::ft_mongodb_app_indexor::DEFAULT_PRIORITY;
+use crate::ft_mongodb_collections;
+
+/// Work queues that can be refilled from data already stored in MongoDB.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedTarget {
+    /// `events_participation_index`, one page-1 entry per stored profile.
+    EventsParticipation,
+    /// `location_index`, one page-1 entry per stored profile.
+    Locations,
+    /// `events_ids`, every event id seen in stored participations.
+    Events,
+}
+
+/// How seeding treats ids that are already queued or already fetched.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SeedDedupe {
+    /// Leave queued entries as they are, only add missing ids.
+    KeepExisting,
+    /// Overwrite queued entries, restarting them from their first page.
+    Reset,
+    /// Like `keep-existing`, and also skip ids whose data is already stored.
+    SkipStored,
+}
+
+impl SeedTarget {
+    pub fn queue_name(&self) -> &str {
+        match self {
+            SeedTarget::EventsParticipation => ft_mongodb_collections::PARTICIPATION_QUEUE,
+            SeedTarget::Locations => ft_mongodb_collections::LOCATION_QUEUE,
+            SeedTarget::Events => ft_mongodb_collections::EVENT_QUEUE,
+        }
+    }
+
+    fn source_collection(&self) -> &str {
+        match self {
+            SeedTarget::EventsParticipation | SeedTarget::Locations => {
+                ft_mongodb_collections::PROFILES
+            }
+            SeedTarget::Events => ft_mongodb_collections::EVENT_PARTICIPATIONS,
+        }
+    }
+
+    fn stored_collection(&self) -> &str {
+        match self {
+            SeedTarget::EventsParticipation => ft_mongodb_collections::EVENT_PARTICIPATIONS,
+            SeedTarget::Locations => ft_mongodb_collections::LOCATIONS,
+            SeedTarget::Events => ft_mongodb_collections::EVENTS,
+        }
+    }
+
+    /// Field of `stored_collection` holding the id that is queued.
+    fn stored_key(&self) -> &str {
+        match self {
+            SeedTarget::Locations | SeedTarget::EventsParticipation => "user_id",
+            SeedTarget::Events => "_id",
+        }
+    }
+}
+
+/// Fills the queue of `target`, returning how many ids the aggregation
+/// produced. With `dry_run` nothing is written and only the count is returned.
+pub async fn seed_queue(
+    client: &Client,
+    target: SeedTarget,
+    dedupe: SeedDedupe,
+    dry_run: bool,
+) -> Result<u64, Box<dyn Error>> {
+    let collection: Collection<Document> = data_collection(client, target.source_collection());
+    let mut pipeline = build_seed_pipeline(target, dedupe);
+    let candidates = count_candidates(&collection, pipeline.clone()).await?;
+    if dry_run {
+        info!(
+            "Dry run: {} ids would be seeded into {}.",
+            candidates,
+            target.queue_name()
+        );
+        return Ok(candidates);
+    }
+    pipeline.push(merge_stage(target, dedupe));
+    collection.aggregate(pipeline).await.map_err(|e| {
+        error!("Failed to seed {} in MongoDB: {}", target.queue_name(), e);
+        e
+    })?;
+    info!("Seeded {} ids into {}.", candidates, target.queue_name());
+    Ok(candidates)
+}
+
+/// Number of documents `pipeline` would produce.
+pub async fn count_candidates(
+    collection: &Collection<Document>,
+    mut pipeline: Vec<Document>,
+) -> Result<u64, Box<dyn Error>> {
+    pipeline.push(doc! {"$count": "candidates"});
+    let counts: Vec<Document> = collection.aggregate(pipeline).await?.try_collect().await?;
+    let candidates = counts
+        .first()
+        .and_then(|doc| match doc.get("candidates") {
+            Some(Bson::Int32(count)) => Some(*count as u64),
+            Some(Bson::Int64(count)) => Some(*count as u64),
+            _ => None,
+        })
+        .unwrap_or(0);
+    Ok(candidates)
+}
+
+fn build_seed_pipeline(target: SeedTarget, dedupe: SeedDedupe) -> Vec<Document> {
+    let mut pipeline = match target {
+        SeedTarget::EventsParticipation | SeedTarget::Locations => vec![
+            doc! {"$project": {"_id": 1}},
+            doc! {"$addFields": {"page_number": 1, "priority": DEFAULT_PRIORITY}},
+        ],
+        SeedTarget::Events => vec![
+            // A participation without an event would queue a `null` id.
+            doc! {"$match": {"event_id": {"$ne": null}}},
+            doc! {"$group": {"_id": "$event_id"}},
+            doc! {"$addFields": {"priority": DEFAULT_PRIORITY}},
+        ],
+    };
+    if dedupe == SeedDedupe::SkipStored {
+        pipeline.push(doc! {"$lookup": {
+            "from": collection_name(target.stored_collection()),
+            "localField": "_id",
+            "foreignField": target.stored_key(),
+            "as": "stored",
+            "pipeline": [{"$limit": 1}, {"$project": {"_id": 1}}],
+        }});
+        pipeline.push(doc! {"$match": {"stored": {"$size": 0}}});
+        pipeline.push(doc! {"$unset": "stored"});
+    }
+    pipeline
+}
+
+fn merge_stage(target: SeedTarget, dedupe: SeedDedupe) -> Document {
+    let when_matched = match dedupe {
+        SeedDedupe::Reset => "replace",
+        SeedDedupe::KeepExisting | SeedDedupe::SkipStored => "keepExisting",
+    };
+    doc! {"$merge": {
+        "into": {"db": app_database_name(), "coll": collection_name(target.queue_name())},
+        "whenMatched": when_matched,
+        "whenNotMatched": "insert",
+    }}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seed_pipeline_for_events_groups_participations() {
+        let pipeline = build_seed_pipeline(SeedTarget::Events, SeedDedupe::KeepExisting);
+        assert_eq!(pipeline[0], doc! {"$match": {"event_id": {"$ne": null}}});
+        assert_eq!(pipeline[1], doc! {"$group": {"_id": "$event_id"}});
+        assert_eq!(pipeline.len(), 3);
+    }
+
+    #[test]
+    fn test_seed_pipeline_skip_stored_looks_up_stored_data() {
+        let pipeline = build_seed_pipeline(SeedTarget::Locations, SeedDedupe::SkipStored);
+        let lookup = pipeline[2].get_document("$lookup").unwrap();
+        assert_eq!(lookup.get_str("from").unwrap(), "locations");
+        assert_eq!(lookup.get_str("foreignField").unwrap(), "user_id");
+    }
+
+    #[test]
+    fn test_merge_stage_reset_replaces_queued_entries() {
+        let merge = merge_stage(SeedTarget::EventsParticipation, SeedDedupe::Reset);
+        let merge = merge.get_document("$merge").unwrap();
+        assert_eq!(merge.get_str("whenMatched").unwrap(), "replace");
+        assert_eq!(
+            merge.get_document("into").unwrap(),
+            &doc! {"db": app_database_name(), "coll": ft_mongodb_collections::PARTICIPATION_QUEUE}
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use std::{env, error::Error, future::Future, sync::OnceLock, time::Duration};
+
+use futures::TryStreamExt;
+use log::{debug, error, info, warn};
+use mongodb::{
+    Client, Collection,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+};
+use tokio::{
+    task::JoinHandle,
+    time::{Instant, sleep},
+};
+
+use crate::config::app_collection;
+use crate::ft_mongodb_collections::{self, QUEUES};
+use crate::ft_mongodb_dry_run;
+use crate::shutdown::Shutdown;
+
+const WORKERS_COLLECTION_NAME: &str = ft_mongodb_collections::WORKERS;
+const LOCKS_COLLECTION_NAME: &str = ft_mongodb_collections::LOCKS;
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// A worker whose last heartbeat is older than this is considered dead.
+pub const WORKER_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// Locks expire on their own so a crashed holder cannot block the others.
+pub const LOCK_TTL: Duration = Duration::from_secs(60);
+/// How long `with_lock` waits for a busy lock before giving up.
+pub const LOCK_WAIT: Duration = Duration::from_secs(30);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Id of this process, both as a registered worker and as the holder of the
+/// leases it takes.
+pub fn worker_id() -> &'static str {
+    static WORKER_ID: OnceLock<String> = OnceLock::new();
+    WORKER_ID.get_or_init(|| ObjectId::new().to_hex())
+}
+
+/// A running instance of the binary, registered in `application.workers`.
+#[derive(Debug, Clone)]
+pub struct Worker {
+    client: Client,
+    pub id: String,
+}
+
+impl Worker {
+    pub async fn register(client: &Client) -> Result<Worker, Box<dyn Error>> {
+        let worker = Worker {
+            client: client.clone(),
+            id: worker_id().to_string(),
+        };
+        let now = DateTime::now();
+        worker
+            .collection()
+            .insert_one(doc! {
+                "_id": &worker.id,
+                "hostname": env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string()),
+                "pid": std::process::id() as i64,
+                "started_at": now,
+                "heartbeat_at": now,
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to register worker in MongoDB: {}", e);
+                e
+            })?;
+        info!("Registered worker {}.", worker.id);
+        Ok(worker)
+    }
+
+    fn collection(&self) -> Collection<Document> {
+        app_collection(&self.client, WORKERS_COLLECTION_NAME)
+    }
+
+    pub async fn heartbeat(&self) -> mongodb::error::Result<()> {
+        self.collection()
+            .update_one(
+                doc! {"_id": &self.id},
+                doc! {"$set": {"heartbeat_at": DateTime::now()}},
+            )
+            .await?;
+        debug!("Heartbeat sent for worker {}.", self.id);
+        Ok(())
+    }
+
+    /// Sends a heartbeat every `HEARTBEAT_INTERVAL` until stopped.
+    pub fn spawn_heartbeat(&self) -> Heartbeat {
+        let worker = self.clone();
+        let stop = Shutdown::new();
+        let stopped = stop.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let next = Instant::now() + HEARTBEAT_INTERVAL;
+                if let Err(e) = worker.heartbeat().await {
+                    warn!("Failed to send heartbeat for worker {}: {}", worker.id, e);
+                }
+                if !stopped.sleep_until(next).await {
+                    break;
+                }
+            }
+        });
+        Heartbeat { stop, task }
+    }
+
+    pub async fn unregister(&self) -> Result<(), Box<dyn Error>> {
+        self.collection().delete_one(doc! {"_id": &self.id}).await?;
+        info!("Unregistered worker {}.", self.id);
+        Ok(())
+    }
+}
+
+/// A task that keeps a worker or a lock alive in MongoDB.
+pub struct Heartbeat {
+    stop: Shutdown,
+    task: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    /// Lets a running beat finish, then ends the task.
+    pub async fn stop(self) {
+        self.stop.request();
+        if let Err(e) = self.task.await {
+            error!("Heartbeat ended abnormally: {}", e);
+        }
+    }
+}
+
+/// Puts back every item `worker_id` still holds a lease on, without counting
+/// an attempt, so another worker can pick it up right away.
+pub async fn release_leases(client: &Client, worker_id: &str) -> Result<u64, Box<dyn Error>> {
+    let mut released = 0;
+    for queue_name in QUEUES {
+        let result = app_collection::<Document>(client, queue_name)
+            .update_many(
+                doc! {"leased_by": worker_id},
+                vec![doc! {"$unset": ["lease_token", "leased_until", "leased_by"]}],
+            )
+            .await?;
+        released += result.modified_count;
+    }
+    if released > 0 {
+        info!("Worker {} requeued {} leased items.", worker_id, released);
+    }
+    Ok(released)
+}
+
+/// Workers that sent a heartbeat within `WORKER_TIMEOUT`.
+pub async fn get_alive_workers(client: &Client) -> Result<Vec<Document>, Box<dyn Error>> {
+    let collection: Collection<Document> = app_collection(client, WORKERS_COLLECTION_NAME);
+    let since = DateTime::from_millis(
+        DateTime::now().timestamp_millis() - WORKER_TIMEOUT.as_millis() as i64,
+    );
+    let workers = collection
+        .find(doc! {"heartbeat_at": {"$gte": since}})
+        .sort(doc! {"started_at": 1})
+        .await?
+        .try_collect()
+        .await?;
+    Ok(workers)
+}
+
+/// A lock held in `application.locks` until released or expired.
+#[derive(Debug)]
+pub struct LockGuard {
+    client: Client,
+    pub name: String,
+    pub owner: ObjectId,
+}
+
+impl LockGuard {
+    /// Pushes the expiry `ttl` from now. `false` once the lock expired and
+    /// another caller took it.
+    pub async fn renew(&self, ttl: Duration) -> Result<bool, Box<dyn Error>> {
+        let result = locks_collection(&self.client)
+            .update_one(
+                doc! {"_id": &self.name, "owner": self.owner},
+                doc! {"$set": {"expires_at": DateTime::now().saturating_add_duration(ttl)}},
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    /// Renews the lock every third of `ttl` until stopped, so a job that
+    /// outlives `ttl` keeps it.
+    fn spawn_heartbeat(&self, ttl: Duration) -> Heartbeat {
+        let guard = LockGuard {
+            client: self.client.clone(),
+            name: self.name.clone(),
+            owner: self.owner,
+        };
+        let stop = Shutdown::new();
+        let stopped = stop.clone();
+        let task = tokio::spawn(async move {
+            while stopped.sleep(ttl / 3).await {
+                match guard.renew(ttl).await {
+                    Ok(true) => debug!("Renewed lock {}.", guard.name),
+                    Ok(false) => {
+                        error!(
+                            "Lock {} expired and was taken by another worker.",
+                            guard.name
+                        );
+                        break;
+                    }
+                    Err(e) => warn!("Failed to renew lock {}: {}", guard.name, e),
+                }
+            }
+        });
+        Heartbeat { stop, task }
+    }
+
+    pub async fn release(self) -> Result<(), Box<dyn Error>> {
+        locks_collection(&self.client)
+            .delete_one(doc! {"_id": &self.name, "owner": self.owner})
+            .await?;
+        debug!("Released lock {}.", self.name);
+        Ok(())
+    }
+}
+
+fn locks_collection(client: &Client) -> Collection<Document> {
+    app_collection(client, LOCKS_COLLECTION_NAME)
+}
+
+/// Takes the lock `name` if it is free or expired, `None` if someone else
+/// holds it. The upsert fails with a duplicate key when the lock is held, so
+/// only one caller can win.
+pub async fn try_acquire_lock(
+    client: &Client,
+    name: &str,
+    ttl: Duration,
+) -> Result<Option<LockGuard>, Box<dyn Error>> {
+    let owner = ObjectId::new();
+    let now = DateTime::now();
+    let result = locks_collection(client)
+        .update_one(
+            doc! {"_id": name, "expires_at": {"$lte": now}},
+            doc! {"$set": {
+                "owner": owner,
+                "acquired_at": now,
+                "expires_at": now.saturating_add_duration(ttl),
+            }},
+        )
+        .upsert(true)
+        .await;
+    match result {
+        Ok(_) => {
+            debug!("Acquired lock {}.", name);
+            Ok(Some(LockGuard {
+                client: client.clone(),
+                name: name.to_string(),
+                owner,
+            }))
+        }
+        Err(e) if is_duplicate_key(&e) => Ok(None),
+        Err(e) => {
+            error!("Failed to acquire lock {}: {}", name, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Runs `job` while holding the lock `name`, waiting up to `LOCK_WAIT` for it.
+/// The lock is renewed while `job` runs. An error of `job` wins over one
+/// releasing the lock.
+pub async fn with_lock<T, F, Fut>(client: &Client, name: &str, job: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    if ft_mongodb_dry_run::skips(&locks_collection(client), "locking") {
+        return job().await;
+    }
+    let deadline = Instant::now() + LOCK_WAIT;
+    let guard = loop {
+        if let Some(guard) = try_acquire_lock(client, name, LOCK_TTL).await? {
+            break guard;
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Lock {} is still held by another worker", name).into());
+        }
+        sleep(LOCK_RETRY_INTERVAL).await;
+    };
+    let heartbeat = guard.spawn_heartbeat(LOCK_TTL);
+    let result = job().await;
+    heartbeat.stop().await;
+    let released = guard.release().await;
+    let value = result?;
+    released?;
+    Ok(value)
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error))
+            if write_error.code == DUPLICATE_KEY_CODE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ft_mongodb_app_indexor::{EventId, QueuePriority, WorkQueue};
+    use mongodb::options::ClientOptions;
+    use testcontainers::{
+        ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
+    };
+
+    pub async fn get_test_mongo_client() -> (Client, ContainerAsync<GenericImage>) {
+        let container = match GenericImage::new("mongo", "latest")
+            .with_exposed_port(27017.tcp())
+            .start()
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Failed to start MongoDB container: {}", e);
+            }
+        };
+
+        // Get the host and port
+        let port = match container.get_host_port_ipv4(27017).await {
+            Ok(p) => p,
+            Err(e) => {
+                panic!("Failed to get MongoDB container port: {}", e);
+            }
+        };
+
+        let client_uri = format!("mongodb://localhost:{}/", port);
+        let options = ClientOptions::parse(&client_uri).await.unwrap();
+        let client = Client::with_options(options).unwrap();
+
+        let db = client.database("admin");
+        for _ in 0..10 {
+            match db.run_command(doc! {"ping": 1}).await {
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Waiting for MongoDB to be ready: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+
+        (client, container)
+    }
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive_until_released() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let guard = try_acquire_lock(&client, "mode", LOCK_TTL).await?.unwrap();
+        assert!(try_acquire_lock(&client, "mode", LOCK_TTL).await?.is_none());
+        guard.release().await?;
+        assert!(try_acquire_lock(&client, "mode", LOCK_TTL).await?.is_some());
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_renew_fails_once_the_lock_is_taken_over() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let expired = try_acquire_lock(&client, "mode", Duration::ZERO)
+            .await?
+            .unwrap();
+        let guard = try_acquire_lock(&client, "mode", LOCK_TTL).await?.unwrap();
+        assert!(guard.renew(LOCK_TTL).await?);
+        assert!(!expired.renew(LOCK_TTL).await?);
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_registered_worker_is_alive() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let worker = Worker::register(&client).await?;
+        assert_eq!(get_alive_workers(&client).await?.len(), 1);
+        worker.unregister().await?;
+        assert!(get_alive_workers(&client).await?.is_empty());
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_release_leases_requeues_claimed_items() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let queue = WorkQueue::<EventId>::new(&client, ft_mongodb_collections::EVENT_QUEUE);
+        queue
+            .push_many(&[EventId(1), EventId(2)], &QueuePriority::default())
+            .await?;
+        let worker = Worker::register(&client).await?;
+        assert_eq!(queue.claim(2).await?.items.len(), 2);
+        assert!(queue.claim(2).await?.items.is_empty());
+        assert_eq!(release_leases(&client, &worker.id).await?, 2);
+        assert_eq!(queue.claim(2).await?.items.len(), 2);
+        worker.unregister().await?;
+        container.stop().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::{collections::BTreeMap, error::Error, time::Duration};
+
+use log::info;
+use mongodb::{
+    Client, Collection,
+    bson::{Bson, DateTime, Document, doc},
+};
+
+use crate::config::data_collection;
+use crate::ft_mongodb_collections;
+use crate::ft_mongodb_write_buffer;
+
+const COLLECTION_NAME: &str = ft_mongodb_collections::CHANGES;
+/// Cursus of the C piscine in the 42 API.
+pub const PISCINE_CURSUS_ID: i64 = 9;
+/// A first location older than this belongs to a student we only discover
+/// now, not to a new one.
+pub const FIRST_LOGIN_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// A full page of locations may not hold the oldest one.
+const LOCATIONS_PAGE_SIZE: usize = 100;
+
+/// What changed for a student between two fetches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    LevelUp,
+    ProjectValidated,
+    NewAchievement,
+    BlackholeMoved,
+    PoolFinished,
+    FirstLogin,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::LevelUp => "level_up",
+            ChangeKind::ProjectValidated => "project_validated",
+            ChangeKind::NewAchievement => "new_achievement",
+            ChangeKind::BlackholeMoved => "blackhole_moved",
+            ChangeKind::PoolFinished => "pool_finished",
+            ChangeKind::FirstLogin => "first_login",
+        }
+    }
+}
+
+/// One entry of `42.changes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub user_id: i64,
+    pub kind: ChangeKind,
+    /// Kind-specific fields, such as the cursus and both levels of a level up.
+    pub details: Document,
+}
+
+impl ChangeEvent {
+    fn new(user_id: i64, kind: ChangeKind, details: Document) -> Self {
+        ChangeEvent {
+            user_id,
+            kind,
+            details,
+        }
+    }
+
+    fn to_document(&self, detected_at: DateTime) -> Document {
+        doc! {
+            "user_id": self.user_id,
+            "kind": self.kind.as_str(),
+            "detected_at": detected_at,
+            "details": &self.details,
+        }
+    }
+}
+
+/// Events between the stored and the freshly fetched version of a profile.
+pub fn derive_profile_changes(
+    user_id: i64,
+    previous: &Document,
+    current: &Document,
+) -> Vec<ChangeEvent> {
+    let mut events = Vec::new();
+    let previous_cursus = by_id(previous, "cursus_users", "cursus_id");
+    for (cursus_id, cursus) in by_id(current, "cursus_users", "cursus_id") {
+        let Some(before) = previous_cursus.get(&cursus_id) else {
+            continue;
+        };
+        let (from, to) = (level_of(before), level_of(cursus));
+        if to.floor() > from.floor() {
+            events.push(ChangeEvent::new(
+                user_id,
+                ChangeKind::LevelUp,
+                doc! {"cursus_id": cursus_id, "from": from, "to": to},
+            ));
+        }
+        let (from, to) = (before.get("blackholed_at"), cursus.get("blackholed_at"));
+        if is_set(from) && is_set(to) && from != to {
+            events.push(ChangeEvent::new(
+                user_id,
+                ChangeKind::BlackholeMoved,
+                doc! {"cursus_id": cursus_id, "from": from.cloned(), "to": to.cloned()},
+            ));
+        }
+        let end_at = cursus.get("end_at");
+        if cursus_id == PISCINE_CURSUS_ID && !is_set(before.get("end_at")) && is_set(end_at) {
+            events.push(ChangeEvent::new(
+                user_id,
+                ChangeKind::PoolFinished,
+                doc! {"cursus_id": cursus_id, "end_at": end_at.cloned()},
+            ));
+        }
+    }
+    let previous_projects = by_id(previous, "projects_users", "id");
+    for (id, project_user) in by_id(current, "projects_users", "id") {
+        let was_validated = previous_projects
+            .get(&id)
+            .is_some_and(|before| before.get_bool("validated?").unwrap_or(false));
+        if project_user.get_bool("validated?").unwrap_or(false) && !was_validated {
+            let project = project_user.get_document("project").ok();
+            events.push(ChangeEvent::new(
+                user_id,
+                ChangeKind::ProjectValidated,
+                doc! {
+                    "project_id": project.and_then(|project| project.get("id").cloned()),
+                    "slug": project.and_then(|project| project.get("slug").cloned()),
+                    "final_mark": project_user.get("final_mark").cloned(),
+                },
+            ));
+        }
+    }
+    let previous_achievements = by_id(previous, "achievements", "id");
+    for (id, achievement) in by_id(current, "achievements", "id") {
+        if !previous_achievements.contains_key(&id) {
+            events.push(ChangeEvent::new(
+                user_id,
+                ChangeKind::NewAchievement,
+                doc! {"achievement_id": id, "name": achievement.get("name").cloned()},
+            ));
+        }
+    }
+    events
+}
+
+/// A first login when the user had no location stored, `locations` is their
+/// whole history and its oldest entry began within `FIRST_LOGIN_WINDOW` of
+/// `now`.
+pub fn derive_location_changes(
+    user_id: i64,
+    had_locations: bool,
+    locations: &[Document],
+    now: DateTime,
+) -> Vec<ChangeEvent> {
+    if had_locations || locations.len() >= LOCATIONS_PAGE_SIZE {
+        return vec![];
+    }
+    let first = locations
+        .iter()
+        .filter_map(|location| {
+            let begin_at = location.get_str("begin_at").ok()?;
+            Some((DateTime::parse_rfc3339_str(begin_at).ok()?, location))
+        })
+        .min_by_key(|(begin_at, _)| *begin_at);
+    let Some((begin_at, location)) = first else {
+        return vec![];
+    };
+    let window_start = now.timestamp_millis() - FIRST_LOGIN_WINDOW.as_millis() as i64;
+    if begin_at.timestamp_millis() < window_start {
+        return vec![];
+    }
+    vec![ChangeEvent::new(
+        user_id,
+        ChangeKind::FirstLogin,
+        doc! {
+            "location_id": location.get("id").cloned(),
+            "host": location.get("host").cloned(),
+            "begin_at": begin_at,
+        },
+    )]
+}
+
+pub async fn insert_changes_in_mongodb(
+    client: &Client,
+    events: &[ChangeEvent],
+) -> Result<(), Box<dyn Error>> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let documents = change_documents(events, DateTime::now());
+    let collection: Collection<Document> = data_collection(client, COLLECTION_NAME);
+    ft_mongodb_write_buffer::insert(client, &collection, documents).await?;
+    info!(
+        "{} changes recorded for user {}.",
+        events.len(),
+        events[0].user_id
+    );
+    Ok(())
+}
+
+/// The entries of `42.changes` for `events`, all detected at `detected_at`.
+pub fn change_documents(events: &[ChangeEvent], detected_at: DateTime) -> Vec<Document> {
+    events
+        .iter()
+        .map(|event| event.to_document(detected_at))
+        .collect()
+}
+
+/// The documents of the array `field`, keyed by their integer `key` and in
+/// its order so events come out the same way every time.
+fn by_id<'a>(profile: &'a Document, field: &str, key: &str) -> BTreeMap<i64, &'a Document> {
+    let Ok(items) = profile.get_array(field) else {
+        return BTreeMap::new();
+    };
+    items
+        .iter()
+        .filter_map(Bson::as_document)
+        .filter_map(|item| Some((as_i64(item.get(key)?)?, item)))
+        .collect()
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn level_of(cursus: &Document) -> f64 {
+    match cursus.get("level") {
+        Some(Bson::Double(level)) => *level,
+        Some(level) => as_i64(level).unwrap_or(0) as f64,
+        None => 0.0,
+    }
+}
+
+fn is_set(value: Option<&Bson>) -> bool {
+    value.is_some_and(|value| *value != Bson::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(level: f64, blackholed_at: &str, validated: bool, achievements: &[i64]) -> Document {
+        doc! {
+            "cursus_users": [
+                {"cursus_id": 21, "level": level, "blackholed_at": blackholed_at},
+                {"cursus_id": PISCINE_CURSUS_ID, "level": 8.5, "end_at": Bson::Null},
+            ],
+            "projects_users": [
+                {"id": 7, "validated?": validated, "final_mark": 100,
+                 "project": {"id": 1314, "slug": "libft"}},
+            ],
+            "achievements": achievements
+                .iter()
+                .map(|id| Bson::Document(doc! {"id": id, "name": format!("a{}", id)}))
+                .collect::<Vec<_>>(),
+        }
+    }
+
+    #[test]
+    fn test_profile_changes_are_typed() {
+        let previous = profile(3.9, "2025-01-01T00:00:00.000Z", false, &[1]);
+        let mut current = profile(4.1, "2025-03-01T00:00:00.000Z", true, &[1, 2]);
+        current.get_array_mut("cursus_users").unwrap()[1]
+            .as_document_mut()
+            .unwrap()
+            .insert("end_at", "2024-08-30T00:00:00.000Z");
+        let kinds: Vec<ChangeKind> = derive_profile_changes(42, &previous, &current)
+            .iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ChangeKind::PoolFinished,
+                ChangeKind::LevelUp,
+                ChangeKind::BlackholeMoved,
+                ChangeKind::ProjectValidated,
+                ChangeKind::NewAchievement,
+            ]
+        );
+        assert!(derive_profile_changes(42, &current, &current).is_empty());
+    }
+
+    #[test]
+    fn test_first_login_only_for_recent_first_locations() {
+        let now = DateTime::parse_rfc3339_str("2025-09-10T00:00:00Z").unwrap();
+        let locations = [
+            doc! {"id": 2, "host": "e1r1p2", "begin_at": "2025-09-05T08:00:00.000Z"},
+            doc! {"id": 1, "host": "e1r1p1", "begin_at": "2025-09-01T08:00:00.000Z"},
+        ];
+        let events = derive_location_changes(42, false, &locations, now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].details.get_i32("location_id").unwrap(), 1);
+        assert!(derive_location_changes(42, true, &locations, now).is_empty());
+        let later = DateTime::parse_rfc3339_str("2026-01-01T00:00:00Z").unwrap();
+        assert!(derive_location_changes(42, false, &locations, later).is_empty());
+    }
+}
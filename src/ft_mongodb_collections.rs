@@ -0,0 +1,68 @@
+// Logical name of every collection the binary reads or writes. Handles come
+// from `config::data_collection` and `config::app_collection`, which turn
+// these names into the ones used in MongoDB: renamed by `[collections]` and
+// prefixed by `mongodb.collection_prefix`. Never write a collection name as
+// a literal elsewhere.
+
+/// Profiles of the 42 API, one per user.
+pub const PROFILES: &str = "profiles";
+/// Previous values of the profile fields each fetch changed.
+pub const PROFILES_HISTORY: &str = "profiles_history";
+pub const LOCATIONS: &str = "locations";
+pub const EVENTS: &str = "events";
+pub const EVENT_PARTICIPATIONS: &str = "event_participations";
+/// Typed events derived from what each fetch changed.
+pub const CHANGES: &str = "changes";
+pub const IGNORING_ID: &str = "ignoring_id";
+pub const FAILED_ID: &str = "failed_id";
+
+/// Next user id to reserve when fetching profiles by range.
+pub const INDEX: &str = "index";
+pub const LAST_UPDATE: &str = "last_update";
+pub const MODE: &str = "mode";
+pub const MODE_HISTORY: &str = "mode_history";
+pub const WORKERS: &str = "workers";
+pub const LOCKS: &str = "locks";
+pub const RUNS: &str = "runs";
+pub const QUEUE_THROUGHPUT: &str = "queue_throughput";
+pub const MIGRATIONS: &str = "migrations";
+pub const PROFILES_INDEX: &str = "profiles_index";
+
+pub const PROFILE_QUEUE: &str = "profile_index_to_be_updated";
+pub const LOCATION_QUEUE: &str = "location_index";
+pub const EVENT_QUEUE: &str = "events_ids";
+pub const PARTICIPATION_QUEUE: &str = "events_participation_index";
+
+/// Every work queue, their dead letters aside.
+pub const QUEUES: [&str; 4] = [
+    LOCATION_QUEUE,
+    PARTICIPATION_QUEUE,
+    EVENT_QUEUE,
+    PROFILE_QUEUE,
+];
+
+/// Every logical name above, for checking the keys of `[collections]`.
+pub const ALL: [&str; 22] = [
+    PROFILES,
+    PROFILES_HISTORY,
+    LOCATIONS,
+    EVENTS,
+    EVENT_PARTICIPATIONS,
+    CHANGES,
+    IGNORING_ID,
+    FAILED_ID,
+    INDEX,
+    LAST_UPDATE,
+    MODE,
+    MODE_HISTORY,
+    WORKERS,
+    LOCKS,
+    RUNS,
+    QUEUE_THROUGHPUT,
+    MIGRATIONS,
+    PROFILES_INDEX,
+    PROFILE_QUEUE,
+    LOCATION_QUEUE,
+    EVENT_QUEUE,
+    PARTICIPATION_QUEUE,
+];
@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use log::info;
+use mongodb::{
+    Collection,
+    bson::{Bson, Document, doc},
+};
+
+use crate::config;
+
+/// Whether writers print what they would write instead of writing it.
+pub fn is_enabled() -> bool {
+    config::get().features.dry_run
+}
+
+/// In dry-run mode, prints each document a write to `collection` would have
+/// stored as one JSON line on stdout and returns `true`, the write must then
+/// be skipped.
+pub fn dumps<'a, T: Send + Sync>(
+    collection: &Collection<T>,
+    documents: impl IntoIterator<Item = &'a Document>,
+) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    for document in documents {
+        println!(
+            "{}",
+            dump_line(&collection.namespace().to_string(), document)
+        );
+    }
+    true
+}
+
+/// In dry-run mode, logs that `action` on `collection` is skipped and returns
+/// `true`. Meant for the bookkeeping writes that store no fetched data.
+pub fn skips<T: Send + Sync>(collection: &Collection<T>, action: &str) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    info!("Dry run: {} on {} skipped.", action, collection.namespace());
+    true
+}
+
+/// Ids of the queue items this process handed out in dry-run mode, per queue
+/// namespace. Those items are only read and stay due, so without this a dry
+/// run would read the head of a queue forever.
+static HANDED_OUT: LazyLock<Mutex<HashMap<String, Vec<i64>>>> = LazyLock::new(Default::default);
+
+/// Ids of the items of the queue at `namespace` already handed out.
+pub fn handed_out(namespace: &str) -> Vec<i64> {
+    let handed_out = HANDED_OUT.lock().unwrap();
+    handed_out.get(namespace).cloned().unwrap_or_default()
+}
+
+/// Records that the items `ids` of the queue at `namespace` were handed out,
+/// so the next reads skip them.
+pub fn hand_out(namespace: &str, ids: impl IntoIterator<Item = i64>) {
+    let mut handed_out = HANDED_OUT.lock().unwrap();
+    handed_out
+        .entry(namespace.to_string())
+        .or_default()
+        .extend(ids);
+}
+
+fn dump_line(namespace: &str, document: &Document) -> String {
+    Bson::Document(doc! {"dry_run": namespace, "document": document})
+        .into_relaxed_extjson()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_line_names_the_target_collection() {
+        let line = dump_line("42.profiles", &doc! {"_id": 42, "login": "norminet"});
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["dry_run"], "42.profiles");
+        assert_eq!(value["document"]["login"], "norminet");
+        assert_eq!(value["document"]["_id"], 42);
+    }
+
+    #[test]
+    fn test_handed_out_ids_are_kept_per_queue() {
+        hand_out("test.handed_out_a", [1, 2]);
+        hand_out("test.handed_out_a", [3]);
+        hand_out("test.handed_out_b", [4]);
+        assert_eq!(handed_out("test.handed_out_a"), vec![1, 2, 3]);
+        assert_eq!(handed_out("test.handed_out_b"), vec![4]);
+        assert!(handed_out("test.handed_out_c").is_empty());
+    }
+}
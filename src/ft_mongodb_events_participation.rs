@@ -3,11 +3,17 @@ use std::error::Error;
 use log::{error, info};
 use mongodb::{
     Client,
-    bson::{Document, doc},
+    bson::{DateTime, Document, doc},
 };
 
-const DATABASE_NAME: &str = "42";
-const COLLECTION_NAME: &str = "event_participations";
+use crate::config::data_collection;
+use crate::fetching_pipeline::Pipeline;
+use crate::ft_mongodb_collections;
+use crate::ft_mongodb_migrations;
+use crate::ft_mongodb_write_buffer;
+use crate::slimming;
+
+const COLLECTION_NAME: &str = ft_mongodb_collections::EVENT_PARTICIPATIONS;
 
 pub async fn insert_user_events_in_mongodb(
     client: &Client,
@@ -15,56 +21,57 @@ pub async fn insert_user_events_in_mongodb(
     events_node: &serde_json::Value,
 ) -> Result<usize, Box<dyn Error>> {
     info!("Inserting events in MongoDB for user {}.", user_id);
-    let events = map_events_to_bson_documents(events_node, user_id);
-    let nb_events = events.len();
-    insert_user_events_into_mongodb(client, user_id, events).await;
+    let participations = map_events_to_bson_documents(events_node, user_id);
+    let nb_events = participations.len();
+    insert_user_events_into_mongodb(client, participations).await?;
     Ok(nb_events)
 }
 
+/// `_id` of the participation of `user_id` in `event_id`, the same on every
+/// fetch so writing a page again replaces its participations.
+pub fn participation_id(user_id: i64, event_id: i64) -> Document {
+    doc! {"user_id": user_id, "event_id": event_id}
+}
+
+/// One participation: both ids, and the event as the API returned it, slimmed
+/// by `slimming.participations`.
 fn convert_json_event_to_bson(event_node: &serde_json::Value, user_id: i64) -> Document {
     let bson_value = mongodb::bson::to_bson(event_node).unwrap();
-    if let mongodb::bson::Bson::Document(doc) = bson_value {
-        return doc! {
-            "event_id": doc.get_i64("id").unwrap(),
+    if let mongodb::bson::Bson::Document(mut event) = bson_value {
+        let event_id = event.get_i64("id").unwrap();
+        slimming::slim(Pipeline::Participations, &mut event);
+        let mut participation = doc! {
+            "_id": participation_id(user_id, event_id),
             "user_id": user_id,
+            "event_id": event_id,
+            "event": event,
+            "fetched_at": DateTime::now(),
         };
+        ft_mongodb_migrations::stamp(COLLECTION_NAME, &mut participation);
+        participation
     } else {
         error!("Expected a document but got a different BSON type.");
         panic!("Expected a document but got a different BSON type.");
     }
 }
 
-async fn insert_user_events_into_mongodb(client: &Client, user_id: i64, events: Vec<Document>) {
-    if events.is_empty() {
+async fn insert_user_events_into_mongodb(
+    client: &Client,
+    participations: Vec<Document>,
+) -> Result<(), Box<dyn Error>> {
+    if participations.is_empty() {
         error!("No events to insert in MongoDB.");
-        return;
+        return Ok(());
     }
-    let colletion = client
-        .database(DATABASE_NAME)
-        .collection::<Document>(COLLECTION_NAME);
-    let query = doc! {"_id": user_id};
-    let update = doc! {
-        "$push": {
-            "events": {
-                "$each": events
-            }
-        }
-    };
-    colletion
-        .update_one(query, update)
-        .upsert(true)
-        .await
-        .unwrap_or_else(|e| {
-            error!("Failed to insert events in MongoDB: {}", e);
-            panic!("Failed to insert events in MongoDB: {}", e);
-        });
+    let collection = data_collection::<Document>(client, COLLECTION_NAME);
+    ft_mongodb_write_buffer::replace(client, &collection, participations).await
 }
 
-fn map_events_to_bson_documents(locations_node: &serde_json::Value, user_id: i64) -> Vec<Document> {
-    match locations_node.as_array() {
+fn map_events_to_bson_documents(events_node: &serde_json::Value, user_id: i64) -> Vec<Document> {
+    match events_node.as_array() {
         Some(array) => array
             .iter()
-            .map(|location_node| convert_json_event_to_bson(location_node, user_id))
+            .map(|event_node| convert_json_event_to_bson(event_node, user_id))
             .collect(),
         None => {
             error!("Expected an array but got a different JSON type.");
@@ -72,3 +79,36 @@ fn map_events_to_bson_documents(locations_node: &serde_json::Value, user_id: i64
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_participations_are_keyed_by_user_and_event() {
+        let events = json!([
+            {"id": 7, "name": "Piscine rush", "kind": "rush"},
+            {"id": 9, "name": "Exam", "kind": "exam"},
+        ]);
+        let participations = map_events_to_bson_documents(&events, 42);
+        assert_eq!(participations.len(), 2);
+        let first = &participations[0];
+        assert_eq!(first.get_document("_id").unwrap(), &participation_id(42, 7));
+        assert_eq!(first.get_i64("event_id").unwrap(), 7);
+        assert_eq!(
+            first
+                .get_document("event")
+                .unwrap()
+                .get_str("kind")
+                .unwrap(),
+            "rush"
+        );
+        assert_eq!(
+            first
+                .get_i32(ft_mongodb_migrations::SCHEMA_VERSION_FIELD)
+                .unwrap(),
+            2
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{Bson, Document, doc},
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Writes the documents of `database.collection` to `output` as JSON lines
+/// in relaxed extended JSON, returning how many were written.
+pub async fn export_collection<W: AsyncWrite + Unpin>(
+    client: &Client,
+    database: &str,
+    collection_name: &str,
+    limit: Option<i64>,
+    output: &mut W,
+) -> Result<u64, Box<dyn Error>> {
+    info!("Exporting {}.{}.", database, collection_name);
+    let collection: Collection<Document> = client.database(database).collection(collection_name);
+    let mut find = collection.find(doc! {}).sort(doc! {"_id": 1});
+    if let Some(limit) = limit {
+        find = find.limit(limit);
+    }
+    let mut cursor = find.await.map_err(|e| {
+        error!("Failed to export {}.{}: {}", database, collection_name, e);
+        e
+    })?;
+    let mut nb_exported = 0;
+    while let Some(document) = cursor.try_next().await? {
+        output.write_all(to_json_line(document).as_bytes()).await?;
+        nb_exported += 1;
+    }
+    output.flush().await?;
+    info!(
+        "Exported {} documents from {}.{}.",
+        nb_exported, database, collection_name
+    );
+    Ok(nb_exported)
+}
+
+fn to_json_line(document: Document) -> String {
+    let mut line = Bson::Document(document).into_relaxed_extjson().to_string();
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::DateTime;
+
+    #[test]
+    fn test_to_json_line_uses_relaxed_extended_json() {
+        let document = doc! {"_id": 42_i64, "at": DateTime::from_millis(0)};
+        assert_eq!(
+            to_json_line(document),
+            "{\"_id\":42,\"at\":{\"$date\":\"1970-01-01T00:00:00Z\"}}\n"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use std::error::Error;
+
+use futures::TryStreamExt;
+use log::{error, info, warn};
+use mongodb::{
+    Client, Collection, IndexModel,
+    bson::{Bson, Document, doc},
+    error::ErrorKind,
+    options::IndexOptions,
+};
+
+use crate::config::{app_collection, data_collection};
+use crate::ft_mongodb_collections::{
+    CHANGES, EVENT_PARTICIPATIONS, EVENTS, LOCATIONS, MODE_HISTORY, PROFILES, PROFILES_HISTORY,
+    QUEUES, RUNS, WORKERS,
+};
+use crate::ft_mongodb_dry_run;
+
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+/// Index MongoDB creates on every collection, never reported as extra.
+const ID_INDEX_NAME: &str = "_id_";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexDatabase {
+    Data,
+    App,
+}
+
+/// One index the binary relies on, on a logical collection name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSpec {
+    pub database: IndexDatabase,
+    pub collection: String,
+    pub keys: Document,
+    pub unique: bool,
+}
+
+impl IndexSpec {
+    fn new(database: IndexDatabase, collection: &str, keys: Document) -> Self {
+        IndexSpec {
+            database,
+            collection: collection.to_string(),
+            keys,
+            unique: false,
+        }
+    }
+
+    /// Refuses two documents with the same keys.
+    fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    /// The name MongoDB would give these keys, like `user_id_1_begin_at_-1`,
+    /// so an index an analyst created by hand with the same keys matches it.
+    pub fn name(&self) -> String {
+        self.keys
+            .iter()
+            .map(|(field, direction)| format!("{}_{}", field, direction_of(direction)))
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    fn collection_handle(&self, client: &Client) -> Collection<Document> {
+        match self.database {
+            IndexDatabase::Data => data_collection(client, &self.collection),
+            IndexDatabase::App => app_collection(client, &self.collection),
+        }
+    }
+
+    fn model(&self) -> IndexModel {
+        IndexModel::builder()
+            .keys(self.keys.clone())
+            .options(
+                IndexOptions::builder()
+                    .name(self.name())
+                    .unique(self.unique.then_some(true))
+                    .build(),
+            )
+            .build()
+    }
+
+    fn matches(&self, existing: &IndexModel) -> bool {
+        let unique = existing
+            .options
+            .as_ref()
+            .and_then(|options| options.unique)
+            .unwrap_or(false);
+        same_keys(&existing.keys, &self.keys) && unique == self.unique
+    }
+}
+
+/// Every index the queries of the binary need: the sorts of the queues and
+/// of `get_biggest_update_at_from_profiles`, the `fetched_at` ranges of the
+/// refresh sweeps and the per-user reads of analysts.
+pub fn declared_indexes() -> Vec<IndexSpec> {
+    use IndexDatabase::{App, Data};
+    let mut specs = vec![
+        IndexSpec::new(Data, PROFILES, doc! {"updated_at": -1}),
+        IndexSpec::new(Data, PROFILES, doc! {"fetched_at": 1}),
+        IndexSpec::new(
+            Data,
+            PROFILES_HISTORY,
+            doc! {"user_id": 1, "fetched_at": -1},
+        ),
+        IndexSpec::new(Data, LOCATIONS, doc! {"user_id": 1, "begin_at": -1}),
+        IndexSpec::new(Data, LOCATIONS, doc! {"fetched_at": 1}),
+        IndexSpec::new(Data, EVENTS, doc! {"fetched_at": 1}),
+        IndexSpec::new(
+            Data,
+            EVENT_PARTICIPATIONS,
+            doc! {"user_id": 1, "event_id": 1},
+        )
+        .unique(),
+        IndexSpec::new(Data, EVENT_PARTICIPATIONS, doc! {"fetched_at": 1}),
+        IndexSpec::new(Data, CHANGES, doc! {"user_id": 1, "detected_at": -1}),
+        IndexSpec::new(Data, CHANGES, doc! {"kind": 1, "detected_at": -1}),
+        IndexSpec::new(App, WORKERS, doc! {"heartbeat_at": 1}),
+        IndexSpec::new(App, RUNS, doc! {"started_at": -1}),
+        IndexSpec::new(App, MODE_HISTORY, doc! {"at": -1}),
+    ];
+    for queue_name in QUEUES {
+        specs.push(IndexSpec::new(
+            App,
+            queue_name,
+            doc! {"priority": -1, "_id": 1},
+        ));
+        specs.push(IndexSpec::new(App, queue_name, doc! {"lease_token": 1}));
+        specs.push(IndexSpec::new(App, queue_name, doc! {"leased_by": 1}));
+    }
+    specs
+}
+
+/// What `reconcile_indexes` found, each index as `namespace.name`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexReport {
+    /// Declared indexes that did not exist, created unless in a dry run.
+    pub missing: Vec<String>,
+    /// Indexes of a declared collection that no spec asks for. They are only
+    /// reported, an analyst may rely on them.
+    pub extra: Vec<String>,
+    /// Collections whose missing indexes could not be created, like a
+    /// unique index over documents a migration has not split yet.
+    pub failed: Vec<String>,
+}
+
+/// Creates the indexes of `specs` that do not exist yet and reports the ones
+/// nobody declared on the same collections. Nothing is dropped. A collection
+/// where creating fails is reported and the others are still reconciled.
+pub async fn reconcile_indexes(
+    client: &Client,
+    specs: &[IndexSpec],
+) -> Result<IndexReport, Box<dyn Error>> {
+    let mut report = IndexReport::default();
+    let mut checked: Vec<String> = Vec::new();
+    for spec in specs {
+        let collection = spec.collection_handle(client);
+        let namespace = collection.namespace().to_string();
+        if checked.contains(&namespace) {
+            continue;
+        }
+        checked.push(namespace.clone());
+        let wanted: Vec<&IndexSpec> = specs
+            .iter()
+            .filter(|other| other.database == spec.database && other.collection == spec.collection)
+            .collect();
+        let existing = list_indexes(&collection).await?;
+        let (missing, extra) = compare_indexes(&wanted, &existing);
+        report.extra.extend(
+            extra
+                .into_iter()
+                .map(|name| format!("{}.{}", namespace, name)),
+        );
+        if missing.is_empty() {
+            continue;
+        }
+        report.missing.extend(
+            missing
+                .iter()
+                .map(|spec| format!("{}.{}", namespace, spec.name())),
+        );
+        if ft_mongodb_dry_run::skips(&collection, "creating indexes") {
+            continue;
+        }
+        match collection
+            .create_indexes(missing.iter().map(|spec| spec.model()))
+            .await
+        {
+            Ok(_) => info!("{} indexes created on {}.", missing.len(), namespace),
+            Err(e) => {
+                error!("Failed to create indexes on {}: {}", namespace, e);
+                report.failed.push(namespace);
+            }
+        }
+    }
+    for name in &report.extra {
+        warn!("Index {} is not declared.", name);
+    }
+    Ok(report)
+}
+
+/// The indexes of `collection`, none if it does not exist yet.
+async fn list_indexes(
+    collection: &Collection<Document>,
+) -> Result<Vec<IndexModel>, Box<dyn Error>> {
+    match collection.list_indexes().await {
+        Ok(cursor) => Ok(cursor.try_collect().await?),
+        Err(e) => match *e.kind {
+            ErrorKind::Command(ref command_error)
+                if command_error.code == NAMESPACE_NOT_FOUND_CODE =>
+            {
+                Ok(vec![])
+            }
+            _ => {
+                error!(
+                    "Failed to list indexes of {}: {}",
+                    collection.namespace(),
+                    e
+                );
+                Err(e.into())
+            }
+        },
+    }
+}
+
+/// The specs no existing index matches, and the names of the existing
+/// indexes no spec matches, `_id_` aside.
+fn compare_indexes<'a>(
+    wanted: &[&'a IndexSpec],
+    existing: &[IndexModel],
+) -> (Vec<&'a IndexSpec>, Vec<String>) {
+    let missing = wanted
+        .iter()
+        .filter(|spec| !existing.iter().any(|index| spec.matches(index)))
+        .copied()
+        .collect();
+    let extra = existing
+        .iter()
+        .filter(|index| !wanted.iter().any(|spec| spec.matches(index)))
+        .filter_map(|index| index.options.as_ref()?.name.clone())
+        .filter(|name| name != ID_INDEX_NAME)
+        .collect();
+    (missing, extra)
+}
+
+/// Same fields in the same order with the same directions, whatever numeric
+/// type they were written with: mongosh writes `1` as a double.
+fn same_keys(existing: &Document, declared: &Document) -> bool {
+    existing.len() == declared.len()
+        && existing.iter().zip(declared).all(
+            |((field, direction), (other_field, other_direction))| {
+                field == other_field && direction_of(direction) == direction_of(other_direction)
+            },
+        )
+}
+
+fn direction_of(direction: &Bson) -> String {
+    match direction {
+        Bson::Int32(direction) => direction.to_string(),
+        Bson::Int64(direction) => direction.to_string(),
+        Bson::Double(direction) => (*direction as i64).to_string(),
+        Bson::String(kind) => kind.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::options::ClientOptions;
+    use testcontainers::{
+        ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
+    };
+
+    pub async fn get_test_mongo_client() -> (Client, ContainerAsync<GenericImage>) {
+        let container = match GenericImage::new("mongo", "latest")
+            .with_exposed_port(27017.tcp())
+            .start()
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Failed to start MongoDB container: {}", e);
+            }
+        };
+
+        let port = match container.get_host_port_ipv4(27017).await {
+            Ok(p) => p,
+            Err(e) => {
+                panic!("Failed to get MongoDB container port: {}", e);
+            }
+        };
+
+        let client_uri = format!("mongodb://localhost:{}/", port);
+        let options = ClientOptions::parse(&client_uri).await.unwrap();
+        let client = Client::with_options(options).unwrap();
+
+        let db = client.database("admin");
+        for _ in 0..10 {
+            match db.run_command(doc! {"ping": 1}).await {
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Waiting for MongoDB to be ready: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+
+        (client, container)
+    }
+
+    fn existing(keys: Document, name: &str) -> IndexModel {
+        IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().name(name.to_string()).build())
+            .build()
+    }
+
+    #[test]
+    fn test_compare_indexes_matches_on_keys() {
+        let specs = declared_indexes();
+        let locations: Vec<&IndexSpec> = specs
+            .iter()
+            .filter(|spec| spec.collection == LOCATIONS)
+            .collect();
+        assert_eq!(locations[0].name(), "user_id_1_begin_at_-1");
+        let indexes = [
+            existing(doc! {"_id": 1}, "_id_"),
+            existing(doc! {"user_id": 1.0, "begin_at": -1.0}, "by_user"),
+            existing(doc! {"host": 1}, "host_1"),
+        ];
+        let (missing, extra) = compare_indexes(&locations, &indexes);
+        assert_eq!(missing, [locations[1]]);
+        assert_eq!(extra, ["host_1"]);
+        let (missing, extra) = compare_indexes(&locations, &[]);
+        assert_eq!(missing.len(), 2);
+        assert!(extra.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_creates_missing_and_reports_extra() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let locations: Collection<Document> = data_collection(&client, LOCATIONS);
+        locations
+            .create_index(IndexModel::builder().keys(doc! {"host": 1}).build())
+            .await?;
+
+        let specs = declared_indexes();
+        let report = reconcile_indexes(&client, &specs).await?;
+        assert!(
+            report
+                .missing
+                .contains(&"42.locations.user_id_1_begin_at_-1".to_string())
+        );
+        assert!(
+            report
+                .missing
+                .contains(&"application.events_ids.priority_-1__id_1".to_string())
+        );
+        assert_eq!(report.extra, ["42.locations.host_1"]);
+        let names = locations.list_index_names().await?;
+        assert!(names.contains(&"user_id_1_begin_at_-1".to_string()));
+
+        let report = reconcile_indexes(&client, &specs).await?;
+        assert!(report.missing.is_empty());
+        container.stop().await?;
+        Ok(())
+    }
+}
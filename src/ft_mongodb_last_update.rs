@@ -3,10 +3,14 @@ use mongodb::{
     bson::{Document, doc},
 };
 
+use crate::config::{app_collection, data_collection};
+use crate::ft_mongodb_collections::{LAST_UPDATE, PROFILES};
+use crate::ft_mongodb_dry_run;
+
 const DEFAULT_LAST_UPDATE: &str = "2020-01-01T00:00:00Z";
 
 pub async fn get_last_update(client: &Client) -> String {
-    let collection: Collection<Document> = client.database("application").collection("last_update");
+    let collection: Collection<Document> = app_collection(client, LAST_UPDATE);
     let last_update = collection.find_one(doc! {}).await;
     match last_update {
         Ok(Some(document)) => document
@@ -18,8 +22,14 @@ pub async fn get_last_update(client: &Client) -> String {
 }
 
 pub async fn update_last_update(client: &Client) {
-    let collection: Collection<Document> = client.database("application").collection("last_update");
+    let collection: Collection<Document> = app_collection(client, LAST_UPDATE);
     let last_update = get_biggest_update_at_from_profiles(client).await;
+    if ft_mongodb_dry_run::dumps(
+        &collection,
+        [&doc! { "_id": 1, "last_update": last_update.as_str() }],
+    ) {
+        return;
+    }
     collection
         .replace_one(
             doc! {"_id": 1},
@@ -31,7 +41,7 @@ pub async fn update_last_update(client: &Client) {
 }
 
 async fn get_biggest_update_at_from_profiles(client: &Client) -> String {
-    let collection: Collection<Document> = client.database("42").collection("profiles");
+    let collection: Collection<Document> = data_collection(client, PROFILES);
     let last_update = collection
         .find_one(doc! {})
         .sort(doc! { "updated_at": -1 })
@@ -101,8 +111,7 @@ mod tests {
         let (client, container) = get_test_mongo_client().await;
 
         // Insert test data into the 'application.last_update' collection
-        let collection: Collection<Document> =
-            client.database("application").collection("last_update");
+        let collection: Collection<Document> = app_collection(&client, LAST_UPDATE);
         collection
             .insert_one(doc! { "last_update": "2023-01-01T00:00:00Z" })
             .await?;
@@ -122,7 +131,7 @@ mod tests {
         let (client, container) = get_test_mongo_client().await;
 
         // Insert test data into the '42.profiles' collection
-        let collection: Collection<Document> = client.database("42").collection("profiles");
+        let collection: Collection<Document> = data_collection(&client, PROFILES);
         collection
             .insert_many(vec![
                 doc! { "updated_at": "2023-01-02T00:00:00Z" },
@@ -146,7 +155,7 @@ mod tests {
         let (client, container) = get_test_mongo_client().await;
 
         // Insert test data into the '42.profiles' collection
-        let collection: Collection<Document> = client.database("42").collection("profiles");
+        let collection: Collection<Document> = data_collection(&client, PROFILES);
         collection
             .insert_many(vec![
                 doc! { "updated_at": "2023-01-02T00:00:00Z" },
@@ -159,8 +168,7 @@ mod tests {
         update_last_update(&client).await;
 
         // Assert the expected result
-        let collection: Collection<Document> =
-            client.database("application").collection("last_update");
+        let collection: Collection<Document> = app_collection(&client, LAST_UPDATE);
         let last_update = collection.find_one(doc! {}).await?;
         assert_eq!(
             last_update.unwrap().get_str("last_update").unwrap(),
@@ -1,7 +1,18 @@
 use std::error::Error;
 
 use log::{error, info};
-use mongodb::{Client, bson::Document};
+use mongodb::{
+    Client,
+    bson::{DateTime, Document, doc},
+};
+
+use crate::config::data_collection;
+use crate::fetching_pipeline::Pipeline;
+use crate::ft_mongodb_changes;
+use crate::ft_mongodb_collections::LOCATIONS;
+use crate::ft_mongodb_migrations;
+use crate::ft_mongodb_write_buffer;
+use crate::slimming;
 
 pub async fn insert_user_locations_in_mongodb(
     client: &Client,
@@ -11,7 +22,22 @@ pub async fn insert_user_locations_in_mongodb(
     info!("Inserting locations in MongoDB for user {}.", user_id);
     let locations = map_locations_to_bson_documents(locations_node, user_id).await;
     let nb_locations = locations.len();
-    insert_user_locations_into_mongodb(client, locations).await;
+    let collection = data_collection::<Document>(client, LOCATIONS);
+    let had_locations = ft_mongodb_write_buffer::is_pending(&collection, |location| {
+        location.get_i64("user_id") == Ok(user_id)
+    }) || collection
+        .find_one(doc! {"user_id": user_id})
+        .projection(doc! {"_id": 1})
+        .await?
+        .is_some();
+    let events = ft_mongodb_changes::derive_location_changes(
+        user_id,
+        had_locations,
+        &locations,
+        DateTime::now(),
+    );
+    ft_mongodb_write_buffer::replace(client, &collection, locations).await?;
+    ft_mongodb_changes::insert_changes_in_mongodb(client, &events).await?;
     Ok(nb_locations)
 }
 
@@ -21,31 +47,19 @@ async fn convert_json_location_to_bson(
 ) -> Document {
     let bson_value = mongodb::bson::to_bson(location_node).unwrap();
     if let mongodb::bson::Bson::Document(mut doc) = bson_value {
+        slimming::slim(Pipeline::Locations, &mut doc);
         doc.insert("user_id", user_id);
         let location_id = doc.get_i64("id").unwrap();
         doc.insert("_id", location_id);
-        doc.remove("user");
-        doc.remove("project");
-        let doc = doc;
-        return doc;
+        doc.insert("fetched_at", DateTime::now());
+        ft_mongodb_migrations::stamp(LOCATIONS, &mut doc);
+        doc
     } else {
         error!("Expected a document but got a different BSON type.");
         panic!("Expected a document but got a different BSON type.");
     }
 }
 
-async fn insert_user_locations_into_mongodb(client: &Client, locations: Vec<Document>) {
-    let result = client
-        .database("42")
-        .collection::<Document>("locations")
-        .insert_many(locations)
-        .ordered(false)
-        .await;
-    if let Err(e) = result {
-        error!("Failed to insert locations in MongoDB: {}", e);
-    }
-}
-
 async fn map_locations_to_bson_documents(
     locations_node: &serde_json::Value,
     user_id: i64,
@@ -55,8 +69,7 @@ async fn map_locations_to_bson_documents(
         .unwrap()
         .iter()
         .map(|location_node| convert_json_location_to_bson(location_node, user_id));
-    let locations = futures::future::join_all(locations).await;
-    locations
+    futures::future::join_all(locations).await
 }
 
 #[cfg(test)]
@@ -133,6 +146,11 @@ mod tests {
         ]);
         let len = insert_user_locations_in_mongodb(&client, user_id, &locations).await?;
         assert_eq!(len, 2);
+        let stored = data_collection::<Document>(&client, LOCATIONS)
+            .find_one(doc! {"_id": 2})
+            .await?
+            .unwrap();
+        assert!(!stored.contains_key("user"));
         container.stop().await?;
         Ok(())
     }
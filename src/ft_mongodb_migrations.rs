@@ -0,0 +1,369 @@
+use std::error::Error;
+
+use futures::TryStreamExt;
+use log::{error, info};
+use mongodb::{
+    Client, Collection,
+    bson::{Bson, DateTime, Document, doc},
+};
+
+use crate::config::{app_collection, data_collection};
+use crate::ft_mongodb_collections::{self, EVENT_PARTICIPATIONS, LOCATIONS};
+use crate::ft_mongodb_dry_run;
+use crate::ft_mongodb_events_participation::participation_id;
+
+const MIGRATIONS_COLLECTION_NAME: &str = ft_mongodb_collections::MIGRATIONS;
+/// Field holding the version of the shape a stored document has.
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+/// Documents upgraded between two progress updates.
+const BATCH_SIZE: i64 = 500;
+
+/// Rewrites one stored document of `collection` into the documents that
+/// replace it: itself changed in place, several documents with other `_id`s,
+/// or none. A document left out of the result is deleted.
+pub type Upgrade = fn(Document) -> Result<Vec<Document>, Box<dyn Error>>;
+
+/// One step of the data database. Migrations run in `version` order, each
+/// over the documents of its collection stamped with a lower version, and
+/// stamp what they write with their own.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub collection: &'static str,
+    pub upgrade: Upgrade,
+}
+
+/// Every migration, oldest first. Append new ones with the next version, and
+/// never change one that may already have run.
+pub const MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 1,
+        name: "locations_drop_user_and_project",
+        collection: LOCATIONS,
+        upgrade: drop_location_user_and_project,
+    },
+    Migration {
+        version: 2,
+        name: "event_participations_one_per_event",
+        collection: EVENT_PARTICIPATIONS,
+        upgrade: split_participation_array,
+    },
+];
+
+/// Version writers stamp on the documents of `collection`, that of the last
+/// migration of the collection.
+pub fn schema_version(collection: &str) -> i32 {
+    MIGRATIONS
+        .iter()
+        .filter(|migration| migration.collection == collection)
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Marks `document` as written in the current shape of `collection`, so no
+/// migration picks it up.
+pub fn stamp(collection: &str, document: &mut Document) {
+    document.insert(SCHEMA_VERSION_FIELD, schema_version(collection));
+}
+
+/// Where a migration stands in `application.migrations`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: &'static str,
+    pub migrated: i64,
+    pub completed: bool,
+}
+
+pub async fn get_migration_statuses(
+    client: &Client,
+) -> Result<Vec<MigrationStatus>, Box<dyn Error>> {
+    let collection: Collection<Document> = app_collection(client, MIGRATIONS_COLLECTION_NAME);
+    let records: Vec<Document> = collection.find(doc! {}).await?.try_collect().await?;
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let record = records
+                .iter()
+                .find(|record| record.get_i32("_id") == Ok(migration.version));
+            let migrated = match record {
+                Some(record) => record.get_i64("migrated").map_err(|e| {
+                    format!(
+                        "Migration {} has a corrupt status: {}",
+                        migration.version, e
+                    )
+                })?,
+                None => 0,
+            };
+            Ok(MigrationStatus {
+                version: migration.version,
+                name: migration.name,
+                migrated,
+                completed: record.is_some_and(|record| record.get_datetime("completed_at").is_ok()),
+            })
+        })
+        .collect()
+}
+
+/// Applies, in order, every migration not completed yet. One interrupted
+/// halfway resumes where it stopped: the documents it already upgraded carry
+/// its version and are not selected again. Returns how many documents each
+/// migration that ran upgraded.
+pub async fn run_migrations(
+    client: &Client,
+    migrations: &[Migration],
+) -> Result<Vec<(i32, i64)>, Box<dyn Error>> {
+    let statuses = get_migration_statuses(client).await?;
+    let mut applied = Vec::new();
+    for migration in migrations {
+        let completed = statuses
+            .iter()
+            .any(|status| status.version == migration.version && status.completed);
+        if completed {
+            continue;
+        }
+        let migrated = run_migration(client, migration).await?;
+        applied.push((migration.version, migrated));
+    }
+    Ok(applied)
+}
+
+async fn run_migration(client: &Client, migration: &Migration) -> Result<i64, Box<dyn Error>> {
+    let collection: Collection<Document> = data_collection(client, migration.collection);
+    let filter = doc! {SCHEMA_VERSION_FIELD: {"$not": {"$gte": migration.version}}};
+    if ft_mongodb_dry_run::skips(&collection, migration.name) {
+        let pending = collection.count_documents(filter).await?;
+        info!("{} documents to migrate with {}.", pending, migration.name);
+        return Ok(0);
+    }
+    let records: Collection<Document> = app_collection(client, MIGRATIONS_COLLECTION_NAME);
+    records
+        .update_one(
+            doc! {"_id": migration.version},
+            doc! {
+                "$set": {"name": migration.name, "collection": migration.collection},
+                "$setOnInsert": {"started_at": DateTime::now(), "migrated": 0_i64},
+            },
+        )
+        .upsert(true)
+        .await?;
+    info!(
+        "Running migration {} {}.",
+        migration.version, migration.name
+    );
+    let mut migrated = 0;
+    loop {
+        let batch: Vec<Document> = collection
+            .find(filter.clone())
+            .limit(BATCH_SIZE)
+            .await?
+            .try_collect()
+            .await?;
+        if batch.is_empty() {
+            break;
+        }
+        let count = batch.len() as i64;
+        for document in batch {
+            upgrade_document(&collection, migration, document).await?;
+        }
+        migrated += count;
+        records
+            .update_one(
+                doc! {"_id": migration.version},
+                doc! {"$inc": {"migrated": count}},
+            )
+            .await?;
+    }
+    records
+        .update_one(
+            doc! {"_id": migration.version},
+            doc! {"$set": {"completed_at": DateTime::now()}},
+        )
+        .await?;
+    info!(
+        "Migration {} {} upgraded {} documents.",
+        migration.version, migration.name, migrated
+    );
+    Ok(migrated)
+}
+
+/// Writes the documents `document` upgrades to, then deletes it unless one
+/// of them kept its `_id`. Running it twice on the same document is harmless.
+async fn upgrade_document(
+    collection: &Collection<Document>,
+    migration: &Migration,
+    document: Document,
+) -> Result<(), Box<dyn Error>> {
+    let id = document.get("_id").cloned();
+    let upgraded = (migration.upgrade)(document).map_err(|e| {
+        error!("{} cannot upgrade document {:?}: {}", migration.name, id, e);
+        e
+    })?;
+    let mut kept = false;
+    for mut document in upgraded {
+        document.insert(SCHEMA_VERSION_FIELD, migration.version);
+        let new_id = document.get("_id").cloned();
+        kept |= new_id == id;
+        collection
+            .replace_one(doc! {"_id": new_id}, document)
+            .upsert(true)
+            .await?;
+    }
+    if !kept {
+        collection.delete_one(doc! {"_id": id}).await?;
+    }
+    Ok(())
+}
+
+/// Locations stored before they were slimmed kept the whole user and project
+/// objects.
+fn drop_location_user_and_project(mut location: Document) -> Result<Vec<Document>, Box<dyn Error>> {
+    location.remove("user");
+    location.remove("project");
+    Ok(vec![location])
+}
+
+/// Participations used to be `$push`ed as `{event_id, user_id}` into an
+/// `events` array of one document per user. Each becomes its own document,
+/// without the event, which the next refresh of the user fetches. A
+/// document without an `events` array is refused rather than deleted.
+fn split_participation_array(user: Document) -> Result<Vec<Document>, Box<dyn Error>> {
+    let id = user.get("_id").cloned().unwrap_or(Bson::Null);
+    let events = user
+        .get_array("events")
+        .map_err(|e| format!("participations {} have no events array: {}", id, e))?;
+    let fetched_at = user.get("fetched_at");
+    events
+        .iter()
+        .map(|event| {
+            let event = event.as_document().ok_or("events holds a non-document")?;
+            let user_id = event.get_i64("user_id")?;
+            let event_id = event.get_i64("event_id")?;
+            let mut participation = doc! {
+                "_id": participation_id(user_id, event_id),
+                "user_id": user_id,
+                "event_id": event_id,
+            };
+            if let Some(fetched_at) = fetched_at {
+                participation.insert("fetched_at", fetched_at.clone());
+            }
+            Ok(participation)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ft_mongodb_collections::PROFILES;
+    use mongodb::options::ClientOptions;
+    use testcontainers::{
+        ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
+    };
+
+    pub async fn get_test_mongo_client() -> (Client, ContainerAsync<GenericImage>) {
+        let container = match GenericImage::new("mongo", "latest")
+            .with_exposed_port(27017.tcp())
+            .start()
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Failed to start MongoDB container: {}", e);
+            }
+        };
+
+        let port = match container.get_host_port_ipv4(27017).await {
+            Ok(p) => p,
+            Err(e) => {
+                panic!("Failed to get MongoDB container port: {}", e);
+            }
+        };
+
+        let client_uri = format!("mongodb://localhost:{}/", port);
+        let options = ClientOptions::parse(&client_uri).await.unwrap();
+        let client = Client::with_options(options).unwrap();
+
+        let db = client.database("admin");
+        for _ in 0..10 {
+            match db.run_command(doc! {"ping": 1}).await {
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Waiting for MongoDB to be ready: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+
+        (client, container)
+    }
+
+    #[test]
+    fn test_migrations_are_ordered_and_versions_follow_them() {
+        let versions: Vec<i32> = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(schema_version(LOCATIONS), 1);
+        assert_eq!(schema_version(PROFILES), 0);
+        let mut location = doc! {"_id": 1, "user": {"id": 42}, "project": null, "host": "e1r1p1"};
+        stamp(LOCATIONS, &mut location);
+        assert_eq!(location.get_i32(SCHEMA_VERSION_FIELD).unwrap(), 1);
+        let upgraded = drop_location_user_and_project(location).unwrap();
+        assert_eq!(
+            upgraded,
+            [doc! {"_id": 1, "host": "e1r1p1", "schema_version": 1}]
+        );
+        let user = doc! {
+            "_id": 42_i64,
+            "events": [
+                {"event_id": 7_i64, "user_id": 42_i64},
+                {"event_id": 7_i64, "user_id": 42_i64},
+                {"event_id": 9_i64, "user_id": 42_i64},
+            ],
+            "fetched_at": null,
+        };
+        let participations = split_participation_array(user).unwrap();
+        assert_eq!(participations.len(), 3);
+        assert_eq!(participations[0], participations[1]);
+        assert_eq!(
+            participations[2].get_document("_id").unwrap(),
+            &participation_id(42, 9)
+        );
+        let error = split_participation_array(doc! {"_id": 1}).unwrap_err();
+        assert!(error.to_string().contains("participations 1 "));
+        let user = doc! {"_id": 42_i64, "events": [{"event_id": 7_i64, "user_id": 42_i64}]};
+        let participations = split_participation_array(user).unwrap();
+        assert!(!participations[0].contains_key("fetched_at"));
+    }
+
+    #[tokio::test]
+    async fn test_migration_resumes_and_records_completion() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let locations: Collection<Document> = data_collection(&client, LOCATIONS);
+        locations
+            .insert_many([
+                doc! {"_id": 1, "user": {"id": 42}, "project": {"id": 1}},
+                doc! {"_id": 2, "user": {"id": 42}, SCHEMA_VERSION_FIELD: 1},
+                doc! {"_id": 3, "host": "e1r1p1"},
+            ])
+            .await?;
+
+        let applied = run_migrations(&client, &MIGRATIONS).await?;
+        assert_eq!(applied, [(1, 2), (2, 0)]);
+        let first = locations.find_one(doc! {"_id": 1}).await?.unwrap();
+        assert!(!first.contains_key("user"));
+        assert_eq!(first.get_i32(SCHEMA_VERSION_FIELD)?, 1);
+        let skipped = locations.find_one(doc! {"_id": 2}).await?.unwrap();
+        assert!(skipped.contains_key("user"));
+
+        assert!(run_migrations(&client, &MIGRATIONS).await?.is_empty());
+        let statuses = get_migration_statuses(&client).await?;
+        assert!(statuses[0].completed);
+        assert_eq!(statuses[0].migrated, 2);
+        container.stop().await?;
+        Ok(())
+    }
+}
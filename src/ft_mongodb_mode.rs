@@ -1,12 +1,27 @@
 use std::error::Error;
 
+use clap::ValueEnum;
+use futures::TryStreamExt;
 use log::{debug, info};
 use mongodb::{
     Client, Collection,
-    bson::{Bson, Document, doc},
+    bson::{Bson, DateTime, Document, doc, document::ValueAccessError},
 };
+use serde::Deserialize;
 
-#[derive(Debug, PartialEq)]
+use crate::config::app_collection;
+use crate::ft_mongodb_app_workers::{with_lock, worker_id};
+use crate::ft_mongodb_collections;
+use crate::ft_mongodb_dry_run;
+use crate::pipeline_config::PipelineConfig;
+
+const MODE_LOCK_NAME: &str = "mode";
+const MODE_COLLECTION_NAME: &str = ft_mongodb_collections::MODE;
+const MODE_HISTORY_COLLECTION_NAME: &str = ft_mongodb_collections::MODE_HISTORY;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[value(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum Mode {
     ProfilesIndexing,
     Profiles,
@@ -17,7 +32,7 @@ pub enum Mode {
 }
 
 impl Mode {
-    pub fn as_str(&self) -> &str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             Mode::ProfilesIndexing => "profiles_indexing",
             Mode::Profiles => "profiles",
@@ -29,72 +44,281 @@ impl Mode {
     }
 }
 
-pub fn get_mode_from_str(mode: &str) -> Mode {
+pub fn get_mode_from_str(mode: &str) -> Result<Mode, Box<dyn Error>> {
     match mode {
-        "profiles_indexing" => Mode::ProfilesIndexing,
-        "profiles" => Mode::Profiles,
-        "locations_indexing" => Mode::LocationsIndexing,
-        "locations" => Mode::Locations,
-        "user_events" => Mode::UserEvents,
-        "events" => Mode::Events,
-        _ => Mode::ProfilesIndexing,
+        "profiles_indexing" => Ok(Mode::ProfilesIndexing),
+        "profiles" => Ok(Mode::Profiles),
+        "locations_indexing" => Ok(Mode::LocationsIndexing),
+        "locations" => Ok(Mode::Locations),
+        "user_events" => Ok(Mode::UserEvents),
+        "events" => Ok(Mode::Events),
+        _ => Err(format!("Unknown mode '{}'", mode).into()),
+    }
+}
+
+/// What is stored in `application.mode`: the stage to dispatch and whether
+/// dispatching is paused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModeState {
+    pub mode: Mode,
+    pub paused: bool,
+}
+
+/// Why the mode changed, as recorded in `application.mode_history`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    /// Moved to another stage of the pipeline by hand.
+    Set,
+    /// Moved to any mode by hand, paused or not.
+    Force,
+    /// Moved to the next stage by hand.
+    Skip,
+    Pause,
+    Resume,
+    /// Moved to the next stage by the dispatcher once a stage is done.
+    Advance,
+}
+
+impl Transition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transition::Set => "set",
+            Transition::Force => "force",
+            Transition::Skip => "skip",
+            Transition::Pause => "pause",
+            Transition::Resume => "resume",
+            Transition::Advance => "advance",
+        }
     }
 }
 
 pub async fn get_current_mode_from_mongo(client: &Client) -> Result<Mode, Box<dyn Error>> {
+    Ok(get_mode_state(client).await?.mode)
+}
+
+pub async fn get_mode_state(client: &Client) -> Result<ModeState, Box<dyn Error>> {
     info!("Fetching current mode from MongoDB.");
-    let collection: Collection<Document> = client.database("application").collection("mode");
-    let found_doc = collection.find_one(doc! { "_id": 1 }).await;
-    if found_doc.is_err() {
-        info!("Failed to find a mode in MongoDB");
-        return Ok(Mode::Profiles);
-    }
-    let current_mode = convert_to_mode(found_doc?);
-    info!("Current mode is {}", current_mode.as_str());
-    Ok(current_mode)
-}
-
-pub async fn update_mode_in_mongo(client: &Client) -> Result<(), Box<dyn Error>> {
-    debug!("Updating mode in MongoDB.");
-    let current_mode = get_current_mode_from_mongo(client).await?;
-    let next_mode = get_next_mode(current_mode);
-    insert_mode_in_mongo(client, next_mode).await?;
-    debug!("Updated mode in MongoDB.");
-    Ok(())
+    let collection: Collection<Document> = app_collection(client, MODE_COLLECTION_NAME);
+    let found_doc = collection.find_one(doc! { "_id": 1 }).await?;
+    if found_doc.is_none() {
+        info!("No mode stored in MongoDB yet, starting with profiles.");
+    }
+    let state = ModeState {
+        paused: convert_to_paused(found_doc.as_ref())?,
+        mode: convert_to_mode(found_doc)?,
+    };
+    info!(
+        "Current mode is {}{}",
+        state.mode.as_str(),
+        if state.paused { " (paused)" } else { "" }
+    );
+    Ok(state)
+}
+
+/// Moves to the next stage of `config` by hand, paused or not.
+pub async fn skip_mode(
+    client: &Client,
+    config: &PipelineConfig,
+    reason: &str,
+) -> Result<ModeState, Box<dyn Error>> {
+    transition(client, Transition::Skip, reason, |state| {
+        Ok(ModeState {
+            mode: config.next_mode(state.mode),
+            ..state
+        })
+    })
+    .await
+}
+
+/// Moves to the next mode only if the current one is still `from`, so
+/// workers that all saw the same stage finish advance it once. Returns the
+/// mode in place afterwards.
+pub async fn advance_mode_from(
+    client: &Client,
+    config: &PipelineConfig,
+    from: Mode,
+) -> Result<Mode, Box<dyn Error>> {
+    debug!("Advancing mode from {} in MongoDB.", from.as_str());
+    let reason = format!("{} stage done", from.as_str());
+    let state = transition(client, Transition::Advance, &reason, |state| {
+        if state.mode != from {
+            return Ok(state);
+        }
+        Ok(ModeState {
+            mode: config.next_mode(state.mode),
+            ..state
+        })
+    })
+    .await?;
+    info!("Mode is now {}", state.mode.as_str());
+    Ok(state.mode)
+}
+
+/// Moves to `mode`, which must be a stage of `config`. Refused while paused
+/// so a paused pipeline is not restarted by mistake.
+pub async fn set_mode_in_mongo(
+    client: &Client,
+    config: &PipelineConfig,
+    mode: Mode,
+    reason: &str,
+) -> Result<ModeState, Box<dyn Error>> {
+    transition(client, Transition::Set, reason, |state| {
+        if state.paused {
+            return Err("Mode is paused, resume it first or force the mode".into());
+        }
+        if config.stage(mode).is_none() {
+            return Err(format!("Mode {} is not a stage of the pipeline", mode.as_str()).into());
+        }
+        Ok(ModeState { mode, ..state })
+    })
+    .await
+}
+
+/// Overwrites the current mode whatever it was, keeping the pause as is.
+pub async fn force_mode(
+    client: &Client,
+    mode: Mode,
+    reason: &str,
+) -> Result<ModeState, Box<dyn Error>> {
+    transition(client, Transition::Force, reason, |state| {
+        Ok(ModeState { mode, ..state })
+    })
+    .await
+}
+
+/// Stops the dispatcher from fetching until `resume_mode`.
+pub async fn pause_mode(client: &Client, reason: &str) -> Result<ModeState, Box<dyn Error>> {
+    transition(client, Transition::Pause, reason, |state| {
+        Ok(ModeState {
+            paused: true,
+            ..state
+        })
+    })
+    .await
+}
+
+pub async fn resume_mode(client: &Client, reason: &str) -> Result<ModeState, Box<dyn Error>> {
+    transition(client, Transition::Resume, reason, |state| {
+        Ok(ModeState {
+            paused: false,
+            ..state
+        })
+    })
+    .await
+}
+
+/// The last `limit` transitions, newest first.
+pub async fn get_mode_history(
+    client: &Client,
+    limit: i64,
+) -> Result<Vec<Document>, Box<dyn Error>> {
+    let collection: Collection<Document> = app_collection(client, MODE_HISTORY_COLLECTION_NAME);
+    let history = collection
+        .find(doc! {})
+        .sort(doc! {"at": -1})
+        .limit(limit)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(history)
 }
 
-async fn insert_mode_in_mongo(client: &Client, mode: Mode) -> Result<(), Box<dyn Error>> {
+/// Applies `change` to the stored state under the `mode` lock, so concurrent
+/// workers cannot interleave a transition, and records it in `mode_history`
+/// when it changed anything. Returns the state in place afterwards.
+async fn transition<F>(
+    client: &Client,
+    transition: Transition,
+    reason: &str,
+    change: F,
+) -> Result<ModeState, Box<dyn Error>>
+where
+    F: FnOnce(ModeState) -> Result<ModeState, Box<dyn Error>>,
+{
+    with_lock(client, MODE_LOCK_NAME, || async move {
+        let before = get_mode_state(client).await?;
+        let after = change(before)?;
+        if after != before {
+            insert_mode_in_mongo(client, after).await?;
+            record_transition(client, transition, before, after, reason).await?;
+        }
+        Ok(after)
+    })
+    .await
+}
+
+async fn insert_mode_in_mongo(client: &Client, state: ModeState) -> Result<(), Box<dyn Error>> {
     debug!("Inserting mode in MongoDB.");
-    let collection: Collection<Document> = client.database("application").collection("mode");
+    let collection: Collection<Document> = app_collection(client, MODE_COLLECTION_NAME);
+    let document = doc! {
+        "_id": 1,
+        "mode": state.mode.as_str(),
+        "paused": state.paused,
+        "updated_at": DateTime::now(),
+    };
+    if ft_mongodb_dry_run::dumps(&collection, [&document]) {
+        return Ok(());
+    }
     collection
-        .insert_one(doc! {"_id": 1, "mode": mode.as_str()})
+        .replace_one(doc! {"_id": 1}, document)
+        .upsert(true)
         .await?;
     debug!("Inserted mode in MongoDB.");
     Ok(())
 }
 
-fn convert_to_mode(found_doc: Option<Document>) -> Mode {
+async fn record_transition(
+    client: &Client,
+    transition: Transition,
+    before: ModeState,
+    after: ModeState,
+    reason: &str,
+) -> Result<(), Box<dyn Error>> {
+    let collection: Collection<Document> = app_collection(client, MODE_HISTORY_COLLECTION_NAME);
+    let entry = doc! {
+        "at": DateTime::now(),
+        "action": transition.as_str(),
+        "from": before.mode.as_str(),
+        "to": after.mode.as_str(),
+        "paused": after.paused,
+        "reason": reason,
+        "worker_id": worker_id(),
+    };
+    if ft_mongodb_dry_run::dumps(&collection, [&entry]) {
+        return Ok(());
+    }
+    collection.insert_one(entry).await?;
+    info!(
+        "Mode {} {} -> {}: {}",
+        transition.as_str(),
+        before.mode.as_str(),
+        after.mode.as_str(),
+        reason
+    );
+    Ok(())
+}
+
+fn convert_to_mode(found_doc: Option<Document>) -> Result<Mode, Box<dyn Error>> {
     match found_doc {
-        Some(doc) => {
-            let bson_mode = doc.get("mode").unwrap();
-            if let Bson::String(mode) = bson_mode {
-                get_mode_from_str(mode)
-            } else {
-                Mode::Profiles
-            }
-        }
-        None => Mode::Profiles,
+        Some(doc) => match doc.get("mode") {
+            Some(Bson::String(mode)) => get_mode_from_str(mode),
+            Some(mode) => Err(format!("Stored mode {} is not a string", mode).into()),
+            None => Err(format!("Stored mode document {} has no 'mode'", doc).into()),
+        },
+        None => Ok(Mode::Profiles),
     }
 }
 
-fn get_next_mode(current_mode: Mode) -> Mode {
-    match current_mode {
-        Mode::ProfilesIndexing => Mode::Profiles,
-        Mode::Profiles => Mode::LocationsIndexing,
-        Mode::LocationsIndexing => Mode::Locations,
-        Mode::Locations => Mode::UserEvents,
-        Mode::UserEvents => Mode::Events,
-        Mode::Events => Mode::ProfilesIndexing,
+/// Documents written before pausing existed have no `paused` field and are
+/// not paused.
+fn convert_to_paused(found_doc: Option<&Document>) -> Result<bool, Box<dyn Error>> {
+    let Some(doc) = found_doc else {
+        return Ok(false);
+    };
+    match doc.get_bool("paused") {
+        Ok(paused) => Ok(paused),
+        Err(ValueAccessError::NotPresent) => Ok(false),
+        Err(_) => Err(format!("Stored mode document {} has a non-bool 'paused'", doc).into()),
     }
 }
 
@@ -145,6 +369,32 @@ mod tests {
         (client, container)
     }
 
+    #[test]
+    fn test_get_mode_from_str_rejects_unknown_modes() {
+        assert_eq!(get_mode_from_str("locations").unwrap(), Mode::Locations);
+        assert!(get_mode_from_str("location").is_err());
+    }
+
+    #[test]
+    fn test_convert_to_mode_rejects_malformed_documents() {
+        assert_eq!(convert_to_mode(None).unwrap(), Mode::Profiles);
+        let mode = convert_to_mode(Some(doc! {"_id": 1, "mode": "events"}));
+        assert_eq!(mode.unwrap(), Mode::Events);
+        let error = convert_to_mode(Some(doc! {"_id": 1, "mode": 3})).unwrap_err();
+        assert!(error.to_string().contains('3'));
+        let error = convert_to_mode(Some(doc! {"_id": 1, "paused": true})).unwrap_err();
+        assert!(error.to_string().contains("paused"));
+    }
+
+    #[test]
+    fn test_convert_to_paused_rejects_a_wrong_type() {
+        assert!(!convert_to_paused(None).unwrap());
+        assert!(!convert_to_paused(Some(&doc! {"_id": 1})).unwrap());
+        assert!(convert_to_paused(Some(&doc! {"_id": 1, "paused": true})).unwrap());
+        let error = convert_to_paused(Some(&doc! {"_id": 1, "paused": "yes"})).unwrap_err();
+        assert!(error.to_string().contains("yes"));
+    }
+
     #[tokio::test]
     async fn test_get_current_mode_from_mongo() {
         let (client, container) = get_test_mongo_client().await;
@@ -156,9 +406,58 @@ mod tests {
     #[tokio::test]
     async fn test_update_mode_in_mongo() {
         let (client, container) = get_test_mongo_client().await;
-        update_mode_in_mongo(&client).await.unwrap();
+        skip_mode(&client, &PipelineConfig::default(), "test")
+            .await
+            .unwrap();
         let mode = get_current_mode_from_mongo(&client).await.unwrap();
         container.stop().await.unwrap();
         assert_eq!(mode, Mode::LocationsIndexing);
     }
+
+    #[tokio::test]
+    async fn test_advance_mode_from_only_moves_the_expected_mode() {
+        let (client, container) = get_test_mongo_client().await;
+        let config = PipelineConfig::default();
+        let first = advance_mode_from(&client, &config, Mode::Profiles)
+            .await
+            .unwrap();
+        let second = advance_mode_from(&client, &config, Mode::LocationsIndexing)
+            .await
+            .unwrap();
+        let stale = advance_mode_from(&client, &config, Mode::LocationsIndexing)
+            .await
+            .unwrap();
+        container.stop().await.unwrap();
+        assert_eq!(first, Mode::LocationsIndexing);
+        assert_eq!(second, Mode::Locations);
+        assert_eq!(stale, Mode::Locations);
+    }
+
+    #[tokio::test]
+    async fn test_pause_blocks_set_and_every_change_is_recorded() {
+        let (client, container) = get_test_mongo_client().await;
+        let config = PipelineConfig::default();
+        pause_mode(&client, "maintenance").await.unwrap();
+        let refused = set_mode_in_mongo(&client, &config, Mode::Events, "test").await;
+        let forced = force_mode(&client, Mode::Events, "hotfix").await.unwrap();
+        let resumed = resume_mode(&client, "done").await.unwrap();
+        let history = get_mode_history(&client, 10).await.unwrap();
+        container.stop().await.unwrap();
+        assert!(refused.is_err());
+        assert_eq!(
+            forced,
+            ModeState {
+                mode: Mode::Events,
+                paused: true
+            }
+        );
+        assert!(!resumed.paused);
+        let actions: Vec<&str> = history
+            .iter()
+            .map(|entry| entry.get_str("action").unwrap())
+            .collect();
+        assert_eq!(actions, ["resume", "force", "pause"]);
+        assert_eq!(history[1].get_str("reason").unwrap(), "hotfix");
+        assert_eq!(history[1].get_str("from").unwrap(), "profiles");
+    }
 }
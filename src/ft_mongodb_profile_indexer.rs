@@ -6,6 +6,10 @@ use mongodb::{
     bson::{Document, doc},
 };
 
+use crate::config::app_collection;
+use crate::ft_mongodb_collections::PROFILES_INDEX;
+use crate::ft_mongodb_dry_run;
+
 pub async fn insert_profiles_index_in_mongodb(
     client: &Client,
     profiles_node: &serde_json::Value,
@@ -22,15 +26,14 @@ async fn map_profiles_index_to_bson_documents(profiles_node: &serde_json::Value)
         .as_array()
         .unwrap()
         .iter()
-        .map(|location_node| convert_json_profile_to_bson(location_node));
-    let locations = futures::future::join_all(locations).await;
-    locations
+        .map(convert_json_profile_to_bson);
+    futures::future::join_all(locations).await
 }
 
 async fn convert_json_profile_to_bson(profile_node: &serde_json::Value) -> Document {
     let bson_value = mongodb::bson::to_bson(profile_node).unwrap();
     if let mongodb::bson::Bson::Document(mut doc) = bson_value {
-        return doc! {"_id": doc.remove("id").unwrap()};
+        doc! {"_id": doc.remove("id").unwrap()}
     } else {
         error!("Expected a document but got a different BSON type.");
         panic!("Expected a document but got a different BSON type.");
@@ -38,12 +41,11 @@ async fn convert_json_profile_to_bson(profile_node: &serde_json::Value) -> Docum
 }
 
 async fn insert_profiles_index_into_mongodb(client: &Client, locations: Vec<Document>) {
-    let result = client
-        .database("application")
-        .collection::<Document>("profiles_index")
-        .insert_many(locations)
-        .ordered(false)
-        .await;
+    let collection = app_collection::<Document>(client, PROFILES_INDEX);
+    if ft_mongodb_dry_run::dumps(&collection, &locations) {
+        return;
+    }
+    let result = collection.insert_many(locations).ordered(false).await;
     if let Err(e) = result {
         error!("Failed to insert locations in MongoDB: {}", e);
     }
@@ -253,9 +255,7 @@ mod tests {
         assert_eq!(result.unwrap(), 4);
 
         // Verify data insertion
-        let collection = client
-            .database("application")
-            .collection::<Document>("profiles_index");
+        let collection = app_collection::<Document>(&client, PROFILES_INDEX);
         let count = collection.count_documents(doc! {}).await.unwrap();
         container.stop().await.unwrap();
         assert_eq!(count, 4);
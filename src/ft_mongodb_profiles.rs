@@ -3,30 +3,42 @@ use std::error::Error;
 use log::{debug, error, info, warn};
 use mongodb::{
     Client, Collection,
-    bson::{Bson, Document, doc},
+    bson::{Bson, DateTime, Document, doc},
 };
 
+use crate::config::{app_collection, data_collection};
+use crate::fetching_pipeline::Pipeline;
+use crate::ft_mongodb_changes;
+use crate::ft_mongodb_collections::{self, CHANGES, PROFILES};
+use crate::ft_mongodb_dry_run;
+use crate::ft_mongodb_migrations::{self, SCHEMA_VERSION_FIELD};
+use crate::ft_mongodb_write_buffer;
+use crate::slimming;
+
+/// Where the counter starts before any id was reserved.
+const FIRST_INDEX: u32 = 28;
+const HISTORY_COLLECTION_NAME: &str = ft_mongodb_collections::PROFILES_HISTORY;
+/// Fields that change on every fetch or migration and say nothing about the
+/// profile.
+const UNTRACKED_FIELDS: [&str; 3] = ["_id", "fetched_at", SCHEMA_VERSION_FIELD];
+
 pub async fn insert_profile_in_mongo(
     client: &Client,
     profile_node: &serde_json::Value,
     user_id: u32,
 ) -> Result<(), Box<dyn Error>> {
     debug!("Inserting profile in MongoDB.");
-    let collection: Collection<Document> = client.database("42").collection("profiles");
+    let collection: Collection<Document> = data_collection(client, PROFILES);
     let bson_value = mongodb::bson::to_bson(&profile_node)?;
     if let Bson::Document(mut doc) = bson_value {
         if let Some(id_value) = doc.get("id").cloned() {
+            slimming::slim(Pipeline::Profiles, &mut doc);
             doc.insert("_id", user_id);
-            let filter = doc! { "_id": user_id };
-            collection
-                .replace_one(filter, doc)
-                .upsert(true)
-                .await
-                .or_else(|e| {
-                    error!("Failed to insert profile in MongoDB: {}", e);
-                    Err(e)
-                })?;
-            info!("mongo : Inserted/Updated document with _id: {:?}", id_value);
+            doc.insert("fetched_at", DateTime::now());
+            ft_mongodb_migrations::stamp(PROFILES, &mut doc);
+            ft_mongodb_write_buffer::replace_tracked(client, &collection, doc, profile_followups)
+                .await?;
+            info!("mongo : Buffered document with _id: {:?}", id_value);
         } else {
             error!("Profil missing 'id' field: {:?}", doc);
         }
@@ -37,12 +49,79 @@ pub async fn insert_profile_in_mongo(
     Ok(())
 }
 
+/// Written once a profile replaced a stored one: in `profiles_history` the
+/// previous value of every field `current` changed, `null` for fields it
+/// added, so any past version of a profile can be rebuilt by walking the
+/// history back from `profiles`, and in `changes` the typed events between
+/// both versions.
+fn profile_followups(previous: &Document, current: &Document) -> Vec<(&'static str, Document)> {
+    let user_id = current.get("_id").cloned().unwrap_or(Bson::Null);
+    let mut followups = Vec::new();
+    let changed = diff_profiles(previous, current);
+    if changed.is_empty() {
+        debug!("Profile {} unchanged.", user_id);
+    } else {
+        info!("{} fields of profile {} changed.", changed.len(), user_id);
+        followups.push((
+            HISTORY_COLLECTION_NAME,
+            doc! {
+                "user_id": &user_id,
+                "fetched_at": current.get("fetched_at").cloned().unwrap_or(Bson::Null),
+                "previous_fetched_at": previous.get("fetched_at").cloned().unwrap_or(Bson::Null),
+                "changed": &changed,
+            },
+        ));
+    }
+    let user_id = match user_id {
+        Bson::Int32(user_id) => user_id as i64,
+        Bson::Int64(user_id) => user_id,
+        _ => return followups,
+    };
+    let events = ft_mongodb_changes::derive_profile_changes(user_id, previous, current);
+    let changes = ft_mongodb_changes::change_documents(&events, DateTime::now());
+    followups.extend(changes.into_iter().map(|change| (CHANGES, change)));
+    followups
+}
+
+/// Top-level fields that differ between the two versions, with their value
+/// in `previous`.
+fn diff_profiles(previous: &Document, current: &Document) -> Document {
+    let tracked = |field: &String| !UNTRACKED_FIELDS.contains(&field.as_str());
+    let mut changed = Document::new();
+    for (field, value) in current.iter().filter(|(field, _)| tracked(field)) {
+        if previous.get(field) != Some(value) {
+            changed.insert(field, previous.get(field).cloned().unwrap_or(Bson::Null));
+        }
+    }
+    for (field, value) in previous.iter().filter(|(field, _)| tracked(field)) {
+        if !current.contains_key(field) {
+            changed.insert(field, value.clone());
+        }
+    }
+    changed
+}
+
+/// Reserves `nb_fetch` ids from the counter. The increment is a single
+/// `$inc` returning the counter before it, so two workers never get the same
+/// range.
 pub async fn fetch_current_index(client: &Client, nb_fetch: u32) -> Result<u32, Box<dyn Error>> {
     info!("Fetching current index from MongoDB.");
-    let collection: Collection<Document> = client.database("application").collection("index");
-    let found_doc = collection.find_one(doc! { "_id": 1 }).await?;
+    let collection: Collection<Document> = app_collection(client, ft_mongodb_collections::INDEX);
+    if ft_mongodb_dry_run::skips(&collection, "reserving ids") {
+        return Ok(obtain_index(collection.find_one(doc! { "_id": 1 }).await?));
+    }
+    let increment = i32::try_from(nb_fetch)?;
+    collection
+        .update_one(
+            doc! {"_id": 1},
+            doc! {"$setOnInsert": {"current_index": FIRST_INDEX as i32}},
+        )
+        .upsert(true)
+        .await?;
+    let found_doc = collection
+        .find_one_and_update(doc! {"_id": 1}, doc! {"$inc": {"current_index": increment}})
+        .await?;
     let current_index = obtain_index(found_doc);
-    increment_index_in_mongo(collection, current_index, nb_fetch).await?;
     info!("Current index is {}", current_index);
     Ok(current_index)
 }
@@ -52,8 +131,12 @@ pub async fn insert_ignoring_id_in_mongo(
     index: u32,
 ) -> Result<(), Box<dyn Error>> {
     debug!("Inserting in MongoDB ignoring id.");
-    let collection: Collection<Document> = client.database("42").collection("ignoring_id");
+    let collection: Collection<Document> =
+        data_collection(client, ft_mongodb_collections::IGNORING_ID);
     let bson_value = mongodb::bson::to_bson(&index)?;
+    if ft_mongodb_dry_run::dumps(&collection, [&doc! {"_id": bson_value.clone()}]) {
+        return Ok(());
+    }
     let result = collection.insert_one(doc! {"_id": bson_value}).await;
     if let Err(e) = result {
         warn!("Failed to insert ignored id for user_id {}: {}", index, e);
@@ -64,8 +147,12 @@ pub async fn insert_ignoring_id_in_mongo(
 
 pub async fn insert_failed_id_in_mongo(client: &Client, index: u32) -> Result<(), Box<dyn Error>> {
     debug!("Inserting in MongoDB failed id.");
-    let collection: Collection<Document> = client.database("42").collection("failed_id");
+    let collection: Collection<Document> =
+        data_collection(client, ft_mongodb_collections::FAILED_ID);
     let bson_value = mongodb::bson::to_bson(&index)?;
+    if ft_mongodb_dry_run::dumps(&collection, [&doc! {"_id": bson_value.clone()}]) {
+        return Ok(());
+    }
     let result = collection.insert_one(doc! {"_id": bson_value}).await;
     if let Err(e) = result {
         warn!("Failed to insert failed id for user_id {}: {}", index, e);
@@ -74,37 +161,16 @@ pub async fn insert_failed_id_in_mongo(client: &Client, index: u32) -> Result<()
     Ok(())
 }
 
-async fn increment_index_in_mongo(
-    collection: Collection<Document>,
-    current_index: u32,
-    nb_fetch: u32,
-) -> Result<(), Box<dyn Error>> {
-    collection
-        .update_one(
-            doc! {
-                "_id": 1
-            },
-            doc! {
-                "$set": {
-                    "current_index": current_index + nb_fetch
-                }
-            },
-        )
-        .upsert(true)
-        .await?;
-    Ok(())
-}
-
 fn obtain_index(found_doc: Option<Document>) -> u32 {
     let current_index: u32 = match found_doc {
         Some(doc) => {
             if let Some(index) = doc.get("current_index").and_then(|v| v.as_i32()) {
                 index as u32
             } else {
-                28
+                FIRST_INDEX
             }
         }
-        None => 28,
+        None => FIRST_INDEX,
     };
     current_index
 }
@@ -114,6 +180,31 @@ mod tests {
     use super::*;
     use mongodb::bson::doc;
 
+    #[test]
+    fn test_diff_profiles_keeps_previous_values_of_changed_fields() {
+        let previous = doc! {
+            "_id": 1,
+            "fetched_at": 1,
+            "login": "norminet",
+            "wallet": 10,
+            "correction_point": 3,
+            "location": "e1r1p1",
+        };
+        let current = doc! {
+            "_id": 1,
+            "fetched_at": 2,
+            "login": "norminet",
+            "wallet": 25,
+            "correction_point": 3,
+            "pool_year": "2024",
+        };
+        assert_eq!(
+            diff_profiles(&previous, &current),
+            doc! {"wallet": 10, "pool_year": Bson::Null, "location": "e1r1p1"}
+        );
+        assert!(diff_profiles(&current, &current).is_empty());
+    }
+
     #[tokio::test]
     async fn test_obtain_index() {
         let found_doc = Some(doc! { "current_index": 42 });
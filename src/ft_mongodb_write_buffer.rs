@@ -0,0 +1,920 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::TryStreamExt;
+use log::{debug, error, info, warn};
+use mongodb::{
+    Client, Collection, Namespace,
+    bson::{Bson, Document, doc, oid::ObjectId},
+    error::{ErrorKind, WriteError},
+    options::{InsertOneModel, ReplaceOneModel, WriteModel},
+};
+use tokio::task::JoinHandle;
+
+use crate::config::{self, BulkConfig, data_collection};
+use crate::ft_mongodb_app_runs;
+use crate::ft_mongodb_dry_run;
+use crate::shutdown::Shutdown;
+
+/// Code of the write error of a duplicate `_id`.
+const DUPLICATE_KEY: i32 = 11000;
+
+tokio::task_local! {
+    /// Buffer of the job running in this task, set by `scope`.
+    static CURRENT: Arc<WriteBuffer>;
+    /// Queue item whose data this future fetches, set by `for_item`.
+    static ITEM: ItemTag;
+}
+
+/// Documents to insert once the replacement of a document is written, each
+/// with the logical name of its data collection, derived from the version
+/// stored before and the replacement.
+pub type Followup = fn(previous: &Document, current: &Document) -> Vec<(&'static str, Document)>;
+
+/// Run once the writes buffered before it are stored, like settling the
+/// queue items whose documents they are.
+pub type AfterFlush = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>> + Send>>;
+
+/// Settles queue items once the writes buffered before it are stored. Gets
+/// the ids of those items the server refused a write for.
+pub type Settle = Box<dyn FnOnce(Vec<i64>) -> AfterFlush + Send>;
+
+/// A queue item a buffered write stores data of.
+#[derive(Debug, Clone, PartialEq)]
+struct ItemTag {
+    queue: String,
+    id: i64,
+}
+
+/// Settlement of the items `ids` of `queue`.
+struct Settlement {
+    queue: String,
+    ids: Vec<i64>,
+    settle: Settle,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WriteKind {
+    Insert,
+    /// Replaces the document with the same `_id`, inserting it if missing.
+    Replace,
+    /// Like `Replace`, and reads the stored version first to run the
+    /// followup once the replacement is written.
+    ReplaceTracked(Followup),
+}
+
+#[derive(Debug, Clone)]
+struct BufferedWrite {
+    namespace: Namespace,
+    document: Document,
+    kind: WriteKind,
+    item: Option<ItemTag>,
+}
+
+impl BufferedWrite {
+    fn id(&self) -> Bson {
+        self.document.get("_id").cloned().unwrap_or(Bson::Null)
+    }
+
+    fn model(&self) -> WriteModel {
+        match self.kind {
+            WriteKind::Insert => InsertOneModel::builder()
+                .namespace(self.namespace.clone())
+                .document(self.document.clone())
+                .build()
+                .into(),
+            WriteKind::Replace | WriteKind::ReplaceTracked(_) => ReplaceOneModel::builder()
+                .namespace(self.namespace.clone())
+                .filter(doc! {"_id": self.id()})
+                .replacement(self.document.clone())
+                .upsert(true)
+                .build()
+                .into(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    writes: Vec<BufferedWrite>,
+    /// Settlements waiting for every write buffered before them.
+    after_flush: Vec<Settlement>,
+    /// Items the server refused a write for, until they are settled.
+    refused: Vec<ItemTag>,
+    /// When the oldest of `writes` was buffered.
+    since: Option<Instant>,
+}
+
+impl Pending {
+    fn push(&mut self, write: BufferedWrite, now: Instant) {
+        self.since.get_or_insert(now);
+        self.writes.push(write);
+    }
+
+    /// Ids of the items of `settlement` a write was refused for, which are
+    /// then forgotten.
+    fn take_refused(&mut self, settlement: &Settlement) -> Vec<i64> {
+        let (refused, others): (Vec<ItemTag>, Vec<ItemTag>) = std::mem::take(&mut self.refused)
+            .into_iter()
+            .partition(|item| item.queue == settlement.queue && settlement.ids.contains(&item.id));
+        self.refused = others;
+        refused.into_iter().map(|item| item.id).collect()
+    }
+
+    fn is_due(&self, bulk: &BulkConfig, now: Instant) -> bool {
+        self.writes.len() >= bulk.max_writes
+            || self
+                .since
+                .is_some_and(|since| now.duration_since(since) >= bulk.max_age())
+    }
+
+    /// Puts back, in front, the writes of a flush that could not be sent so
+    /// the next flush sends them first.
+    fn restore(
+        &mut self,
+        writes: Vec<BufferedWrite>,
+        after_flush: Vec<Settlement>,
+        since: Option<Instant>,
+    ) {
+        self.writes.splice(0..0, writes);
+        self.after_flush.splice(0..0, after_flush);
+        self.since = match (since, self.since) {
+            (Some(since), Some(other)) => Some(since.min(other)),
+            (since, other) => since.or(other),
+        };
+    }
+}
+
+/// One buffered write the server refused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedWrite {
+    pub namespace: String,
+    pub id: Bson,
+    pub message: String,
+}
+
+/// What a flush wrote, followups included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlushReport {
+    pub written: usize,
+    pub failed: Vec<FailedWrite>,
+}
+
+/// Data writes of one job, whatever user or page they come from, sent with
+/// the client of the job in one `bulk_write` once `bulk.max_writes` are
+/// buffered or the oldest waited `bulk.max_age` seconds.
+pub struct WriteBuffer {
+    client: Client,
+    pending: Mutex<Pending>,
+    /// Held by the running flush, so writes put back by a failed flush are
+    /// sent before those buffered after them.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+/// Flushes a buffer on `bulk.max_age` until stopped.
+pub struct Flusher {
+    stop: Shutdown,
+    task: JoinHandle<()>,
+}
+
+impl Flusher {
+    /// Lets a running flush finish, then ends the task.
+    pub async fn stop(self) {
+        self.stop.request();
+        if let Err(e) = self.task.await {
+            error!("Write buffer flusher ended abnormally: {}", e);
+        }
+    }
+}
+
+impl WriteBuffer {
+    pub fn new(client: &Client) -> Self {
+        WriteBuffer {
+            client: client.clone(),
+            pending: Mutex::new(Pending::default()),
+            flushing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Buffers `documents` for `collection`. Returns whether a flush is due.
+    fn push(
+        &self,
+        collection: &Collection<Document>,
+        documents: Vec<Document>,
+        kind: WriteKind,
+    ) -> bool {
+        let item = ITEM.try_with(ItemTag::clone).ok();
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+        for mut document in documents {
+            if matches!(kind, WriteKind::Insert) {
+                with_id(&mut document);
+            }
+            let namespace = collection.namespace();
+            pending.push(
+                BufferedWrite {
+                    namespace,
+                    document,
+                    kind,
+                    item: item.clone(),
+                },
+                now,
+            );
+        }
+        pending.is_due(&config::get().bulk, now)
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .is_due(&config::get().bulk, Instant::now())
+    }
+
+    fn is_pending(
+        &self,
+        collection: &Collection<Document>,
+        predicate: impl Fn(&Document) -> bool,
+    ) -> bool {
+        let namespace = collection.namespace();
+        self.pending
+            .lock()
+            .unwrap()
+            .writes
+            .iter()
+            .any(|write| write.namespace == namespace && predicate(&write.document))
+    }
+
+    /// Writes everything buffered so far in one unordered `bulk_write`, then
+    /// the followups of the replacements that were written, then runs what
+    /// waited for these writes. Refused writes are logged and reported, not
+    /// retried here: their items are handed to their settlement as refused.
+    /// When the server cannot be reached the writes not sent, and what waits
+    /// for them, stay buffered for the next flush, and the error is returned.
+    pub async fn flush(&self) -> Result<FlushReport, Box<dyn Error>> {
+        let _flushing = self.flushing.lock().await;
+        let (writes, after_flush, since) = {
+            let mut pending = self.pending.lock().unwrap();
+            (
+                std::mem::take(&mut pending.writes),
+                std::mem::take(&mut pending.after_flush),
+                pending.since.take(),
+            )
+        };
+        let (previous, refused) = match self.send(&writes).await {
+            Ok(sent) => sent,
+            Err(e) => {
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .restore(writes, after_flush, since);
+                return Err(e);
+            }
+        };
+        let mut report = FlushReport {
+            written: writes.len() - refused.len(),
+            failed: failed_writes(&writes, &refused),
+        };
+        ft_mongodb_app_runs::record_inserted(replaced_count(&writes, &refused) as u64);
+        let refused_items = refused
+            .iter()
+            .filter_map(|(index, _)| writes.get(*index)?.item.clone());
+        self.pending.lock().unwrap().refused.extend(refused_items);
+        let followups = followup_writes(&self.client, &writes, &previous, &refused);
+        let mut followup_error = None;
+        if !followups.is_empty() {
+            match bulk_write(&self.client, &followups).await {
+                Ok(refused) => {
+                    report.written += followups.len() - refused.len();
+                    report.failed.extend(failed_writes(&followups, &refused));
+                }
+                Err(e) => {
+                    followup_error = Some(e.to_string());
+                    self.pending
+                        .lock()
+                        .unwrap()
+                        .restore(followups, vec![], Some(Instant::now()));
+                }
+            }
+        }
+        for failure in &report.failed {
+            error!(
+                "Failed to write {} {} in MongoDB: {}",
+                failure.namespace, failure.id, failure.message
+            );
+        }
+        for settlement in after_flush {
+            let refused = self.pending.lock().unwrap().take_refused(&settlement);
+            if !refused.is_empty() {
+                warn!(
+                    "Items {:?} of {} not settled as fetched, a write was refused.",
+                    refused, settlement.queue
+                );
+            }
+            if let Err(e) = (settlement.settle)(refused).await {
+                error!("Failed to settle after a flush: {}", e);
+            }
+        }
+        if let Some(message) = followup_error {
+            return Err(message.into());
+        }
+        if !writes.is_empty() {
+            info!(
+                "{} buffered writes flushed, {} failed.",
+                report.written,
+                report.failed.len()
+            );
+        }
+        Ok(report)
+    }
+
+    /// The stored versions of the tracked replacements of `writes`, read
+    /// before sending them, and the writes the server refused.
+    async fn send(
+        &self,
+        writes: &[BufferedWrite],
+    ) -> Result<(Vec<Option<Document>>, Vec<(usize, String)>), Box<dyn Error>> {
+        if writes.is_empty() {
+            return Ok((vec![], vec![]));
+        }
+        let previous = find_previous(&self.client, writes).await?;
+        let refused = bulk_write(&self.client, writes).await?;
+        Ok((previous, refused))
+    }
+
+    /// Flushes every `bulk.max_age` once writes are due, so none waits longer
+    /// while the fetch loop sleeps.
+    pub fn spawn_flusher(self: &Arc<Self>) -> Flusher {
+        let buffer = Arc::clone(self);
+        let stop = Shutdown::new();
+        let stopped = stop.clone();
+        let task = tokio::spawn(async move {
+            while stopped.sleep(config::get().bulk.max_age()).await {
+                if buffer.is_due()
+                    && let Err(e) = buffer.flush().await
+                {
+                    error!("Buffered writes not flushed: {}", e);
+                }
+            }
+        });
+        Flusher { stop, task }
+    }
+}
+
+/// Runs `job` with the data writes it makes buffered in `buffer`. Outside of
+/// a scope every write is sent at once with the client it was made with.
+pub async fn scope<T>(buffer: Arc<WriteBuffer>, job: impl Future<Output = T>) -> T {
+    CURRENT.scope(buffer, job).await
+}
+
+fn current() -> Option<Arc<WriteBuffer>> {
+    CURRENT.try_with(Arc::clone).ok()
+}
+
+/// Runs `fetch` with the writes it buffers marked as data of the item `id`
+/// of `queue`, so its settlement learns when one of them is refused.
+pub async fn for_item<T>(queue: &str, id: i64, fetch: impl Future<Output = T>) -> T {
+    let item = ItemTag {
+        queue: queue.to_string(),
+        id,
+    };
+    ITEM.scope(item, fetch).await
+}
+
+/// Buffers `documents` to be inserted in `collection`.
+pub async fn insert(
+    client: &Client,
+    collection: &Collection<Document>,
+    documents: Vec<Document>,
+) -> Result<(), Box<dyn Error>> {
+    push(client, collection, documents, WriteKind::Insert).await
+}
+
+/// Buffers `documents` to replace those of `collection` with the same `_id`.
+pub async fn replace(
+    client: &Client,
+    collection: &Collection<Document>,
+    documents: Vec<Document>,
+) -> Result<(), Box<dyn Error>> {
+    push(client, collection, documents, WriteKind::Replace).await
+}
+
+/// Buffers `document` to replace the one of `collection` with the same `_id`,
+/// then inserts what `followup` derives from both versions. Nothing follows
+/// a document that was not stored yet.
+pub async fn replace_tracked(
+    client: &Client,
+    collection: &Collection<Document>,
+    document: Document,
+    followup: Followup,
+) -> Result<(), Box<dyn Error>> {
+    let kind = WriteKind::ReplaceTracked(followup);
+    push(client, collection, vec![document], kind).await
+}
+
+/// Settles the items `ids` of `queue` once every write the current job
+/// buffered so far is stored, at once outside of a scope. A flush that
+/// cannot reach the server keeps it for the next one, so a queue item is
+/// never settled before its documents are written. `settle` gets the ids of
+/// the items a write was refused for, as marked by `for_item`, which must not
+/// be settled as fetched. With nothing buffered it runs as soon as the
+/// running flush, if any, is done.
+pub async fn after_flush(queue: &str, ids: Vec<i64>, settle: Settle) -> Result<(), Box<dyn Error>> {
+    let Some(buffer) = current() else {
+        return settle(vec![]).await;
+    };
+    let settlement = Settlement {
+        queue: queue.to_string(),
+        ids,
+        settle,
+    };
+    let idle = {
+        let mut pending = buffer.pending.lock().unwrap();
+        pending.after_flush.push(settlement);
+        pending.writes.is_empty()
+    };
+    if idle {
+        buffer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Whether a write buffered by the current job to `collection` holds a
+/// document `predicate` accepts, for reads that must see writes not flushed
+/// yet.
+pub fn is_pending(
+    collection: &Collection<Document>,
+    predicate: impl Fn(&Document) -> bool,
+) -> bool {
+    current().is_some_and(|buffer| buffer.is_pending(collection, predicate))
+}
+
+async fn push(
+    client: &Client,
+    collection: &Collection<Document>,
+    documents: Vec<Document>,
+    kind: WriteKind,
+) -> Result<(), Box<dyn Error>> {
+    if documents.is_empty() || ft_mongodb_dry_run::dumps(collection, &documents) {
+        return Ok(());
+    }
+    match current() {
+        Some(buffer) => {
+            if buffer.push(collection, documents, kind) {
+                buffer.flush().await?;
+            }
+        }
+        None => {
+            let buffer = WriteBuffer::new(client);
+            buffer.push(collection, documents, kind);
+            if let Some(failure) = buffer.flush().await?.failed.first() {
+                return Err(format!(
+                    "Failed to write {} {}: {}",
+                    failure.namespace, failure.id, failure.message
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The stored version of each tracked replacement of `writes`, in the same
+/// order, with one read per collection.
+async fn find_previous(
+    client: &Client,
+    writes: &[BufferedWrite],
+) -> Result<Vec<Option<Document>>, Box<dyn Error>> {
+    let tracked = |write: &&BufferedWrite| matches!(write.kind, WriteKind::ReplaceTracked(_));
+    let mut stored: HashMap<String, Vec<Document>> = HashMap::new();
+    for write in writes.iter().filter(tracked) {
+        let key = write.namespace.to_string();
+        if stored.contains_key(&key) {
+            continue;
+        }
+        let ids: Vec<Bson> = writes
+            .iter()
+            .filter(tracked)
+            .filter(|other| other.namespace == write.namespace)
+            .map(BufferedWrite::id)
+            .collect();
+        let collection: Collection<Document> = client
+            .database(&write.namespace.db)
+            .collection(&write.namespace.coll);
+        let documents = collection
+            .find(doc! {"_id": {"$in": ids}})
+            .await?
+            .try_collect()
+            .await?;
+        stored.insert(key, documents);
+    }
+    Ok(writes
+        .iter()
+        .map(|write| {
+            let id = write.id();
+            stored
+                .get(&write.namespace.to_string())?
+                .iter()
+                .find(|document| document.get("_id") == Some(&id))
+                .cloned()
+        })
+        .collect())
+}
+
+/// Sends `writes` unordered. Returns the index in `writes` and the message
+/// of every write the server refused.
+async fn bulk_write(
+    client: &Client,
+    writes: &[BufferedWrite],
+) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    debug!("Flushing {} buffered writes.", writes.len());
+    let models: Vec<WriteModel> = writes.iter().map(BufferedWrite::model).collect();
+    match client.bulk_write(models).ordered(false).await {
+        Ok(_) => Ok(vec![]),
+        Err(e) => match *e.kind {
+            ErrorKind::BulkWrite(ref bulk) if !bulk.write_concern_errors.is_empty() => {
+                let messages: Vec<&str> = bulk
+                    .write_concern_errors
+                    .iter()
+                    .map(|concern| concern.message.as_str())
+                    .collect();
+                let message = format!("Buffered writes not acknowledged: {}", messages.join("; "));
+                error!("{}", message);
+                Err(message.into())
+            }
+            ErrorKind::BulkWrite(ref bulk) => Ok(refused_writes(writes, &bulk.write_errors)),
+            _ => {
+                error!("Failed to flush {} buffered writes: {}", writes.len(), e);
+                Err(e.into())
+            }
+        },
+    }
+}
+
+/// Documents of the fetched resources written, which all are replacements.
+fn replaced_count(writes: &[BufferedWrite], refused: &[(usize, String)]) -> usize {
+    writes
+        .iter()
+        .enumerate()
+        .filter(|(index, write)| {
+            !matches!(write.kind, WriteKind::Insert)
+                && !refused.iter().any(|(refused, _)| refused == index)
+        })
+        .count()
+}
+
+/// Gives an insert its `_id` before it is buffered, so sending it again after
+/// an unacknowledged flush cannot store it twice.
+fn with_id(document: &mut Document) {
+    if !document.contains_key("_id") {
+        document.insert("_id", ObjectId::new());
+    }
+}
+
+/// The writes the server refused, leaving out inserts refused as duplicates:
+/// an earlier flush, sent again because it was not acknowledged, stored them.
+fn refused_writes(
+    writes: &[BufferedWrite],
+    errors: &HashMap<usize, WriteError>,
+) -> Vec<(usize, String)> {
+    let stored_before = |index: &usize, error: &WriteError| {
+        error.code == DUPLICATE_KEY
+            && writes
+                .get(*index)
+                .is_some_and(|write| matches!(write.kind, WriteKind::Insert))
+    };
+    let mut refused: Vec<(usize, String)> = errors
+        .iter()
+        .filter(|(index, error)| !stored_before(index, error))
+        .map(|(index, error)| (*index, error.message.clone()))
+        .collect();
+    refused.sort();
+    refused
+}
+
+fn failed_writes(writes: &[BufferedWrite], refused: &[(usize, String)]) -> Vec<FailedWrite> {
+    refused
+        .iter()
+        .filter_map(|(index, message)| {
+            let write = writes.get(*index)?;
+            Some(FailedWrite {
+                namespace: write.namespace.to_string(),
+                id: write.id(),
+                message: message.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Inserts the followups of the tracked replacements that were written over
+/// a stored version. They belong to no item: the data of the item is stored
+/// even when one of them is refused.
+fn followup_writes(
+    client: &Client,
+    writes: &[BufferedWrite],
+    previous: &[Option<Document>],
+    refused: &[(usize, String)],
+) -> Vec<BufferedWrite> {
+    writes
+        .iter()
+        .zip(previous)
+        .enumerate()
+        .filter(|(index, _)| !refused.iter().any(|(refused, _)| refused == index))
+        .filter_map(|(_, (write, previous))| match write.kind {
+            WriteKind::ReplaceTracked(followup) => {
+                Some(followup(previous.as_ref()?, &write.document))
+            }
+            _ => None,
+        })
+        .flatten()
+        .map(|(collection, mut document)| {
+            with_id(&mut document);
+            BufferedWrite {
+                namespace: data_collection::<Document>(client, collection).namespace(),
+                document,
+                kind: WriteKind::Insert,
+                item: None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ft_mongodb_app_indexor::{EventId, IndexOutcome, QueuePriority, WorkQueue};
+    use crate::ft_mongodb_collections::{CHANGES, EVENT_QUEUE, PROFILES, PROFILES_HISTORY};
+    use crate::ft_mongodb_profiles;
+    use mongodb::options::ClientOptions;
+    use serde_json::json;
+    use std::time::Duration;
+    use testcontainers::{
+        ContainerAsync, GenericImage, core::IntoContainerPort, runners::AsyncRunner,
+    };
+
+    pub async fn get_test_mongo_client() -> (Client, ContainerAsync<GenericImage>) {
+        let container = match GenericImage::new("mongo", "latest")
+            .with_exposed_port(27017.tcp())
+            .start()
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Failed to start MongoDB container: {}", e);
+            }
+        };
+
+        let port = match container.get_host_port_ipv4(27017).await {
+            Ok(p) => p,
+            Err(e) => {
+                panic!("Failed to get MongoDB container port: {}", e);
+            }
+        };
+
+        let client_uri = format!("mongodb://localhost:{}/", port);
+        let options = ClientOptions::parse(&client_uri).await.unwrap();
+        let client = Client::with_options(options).unwrap();
+
+        let db = client.database("admin");
+        for _ in 0..10 {
+            match db.run_command(doc! {"ping": 1}).await {
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Waiting for MongoDB to be ready: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+
+        (client, container)
+    }
+
+    fn write(id: i32) -> BufferedWrite {
+        BufferedWrite {
+            namespace: Namespace::new("42", "locations"),
+            document: doc! {"_id": id},
+            kind: WriteKind::Replace,
+            item: None,
+        }
+    }
+
+    #[test]
+    fn test_refused_items_go_to_their_own_settlement() {
+        let tag = |queue: &str, id| ItemTag {
+            queue: queue.to_string(),
+            id,
+        };
+        let settlement = |ids: Vec<i64>| Settlement {
+            queue: "location_index".to_string(),
+            ids,
+            settle: Box::new(|_| Box::pin(async { Ok(()) })),
+        };
+        let mut pending = Pending {
+            refused: vec![
+                tag("location_index", 1),
+                tag("events_ids", 1),
+                tag("location_index", 3),
+            ],
+            ..Pending::default()
+        };
+        assert_eq!(pending.take_refused(&settlement(vec![1, 2])), vec![1]);
+        assert_eq!(
+            pending.take_refused(&settlement(vec![1, 2])),
+            Vec::<i64>::new()
+        );
+        assert_eq!(pending.refused.len(), 2);
+        assert_eq!(pending.take_refused(&settlement(vec![3])), vec![3]);
+    }
+
+    #[test]
+    fn test_flush_is_due_on_size_or_age() {
+        let bulk = BulkConfig {
+            max_writes: 2,
+            max_age: 5,
+        };
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        assert!(!pending.is_due(&bulk, start + Duration::from_secs(60)));
+        pending.push(write(1), start);
+        assert!(!pending.is_due(&bulk, start + Duration::from_secs(4)));
+        assert!(pending.is_due(&bulk, start + Duration::from_secs(5)));
+        pending.push(write(2), start + Duration::from_secs(1));
+        assert!(pending.is_due(&bulk, start));
+
+        let Pending { writes, since, .. } = std::mem::take(&mut pending);
+        pending.push(write(3), start + Duration::from_secs(3));
+        pending.restore(writes, vec![], since);
+        let ids: Vec<Bson> = pending.writes.iter().map(BufferedWrite::id).collect();
+        assert_eq!(ids, [Bson::Int32(1), Bson::Int32(2), Bson::Int32(3)]);
+        assert_eq!(pending.since, Some(start));
+    }
+
+    #[test]
+    fn test_failed_writes_name_the_refused_documents() {
+        let writes = [write(1), write(2), write(3)];
+        let refused = [
+            (1, "E11000 duplicate key".to_string()),
+            (7, "?".to_string()),
+        ];
+        let failed = failed_writes(&writes, &refused);
+        assert_eq!(replaced_count(&writes, &refused), 2);
+        assert_eq!(
+            failed,
+            [FailedWrite {
+                namespace: "42.locations".to_string(),
+                id: Bson::Int32(2),
+                message: "E11000 duplicate key".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_inserts_were_stored_by_an_earlier_flush() {
+        let error = |code: i32| -> WriteError {
+            mongodb::bson::from_document(doc! {"code": code, "errmsg": "refused"}).unwrap()
+        };
+        let mut insert = write(2);
+        insert.kind = WriteKind::Insert;
+        let writes = [write(1), insert, write(3)];
+        let errors = HashMap::from([
+            (0, error(DUPLICATE_KEY)),
+            (1, error(DUPLICATE_KEY)),
+            (2, error(121)),
+        ]);
+        let refused: Vec<usize> = refused_writes(&writes, &errors)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(refused, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_inserts_get_an_id_once() {
+        let mut document = doc! {"kind": "level_up"};
+        with_id(&mut document);
+        let id = document.get_object_id("_id").unwrap();
+        with_id(&mut document);
+        assert_eq!(document.get_object_id("_id").unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn test_tracked_replacements_write_history_and_changes() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let profile = |level: f64, wallet: i32| {
+            json!({
+                "id": 42,
+                "login": "norminet",
+                "wallet": wallet,
+                "cursus_users": [{"cursus_id": 21, "level": level}],
+            })
+        };
+        let profiles: Collection<Document> = data_collection(&client, PROFILES);
+        let buffer = Arc::new(WriteBuffer::new(&client));
+        let settled = Arc::new(Mutex::new(false));
+
+        let first = profile(3.9, 10);
+        scope(Arc::clone(&buffer), async {
+            ft_mongodb_profiles::insert_profile_in_mongo(&client, &first, 42).await?;
+            let settled = Arc::clone(&settled);
+            let settle: Settle = Box::new(move |refused| {
+                Box::pin(async move {
+                    assert!(refused.is_empty());
+                    *settled.lock().unwrap() = true;
+                    Ok(())
+                })
+            });
+            after_flush("profile_index", vec![42], settle).await
+        })
+        .await?;
+        assert!(profiles.find_one(doc! {"_id": 42}).await?.is_none());
+        assert!(!*settled.lock().unwrap());
+        let report = buffer.flush().await?;
+        assert_eq!(report.written, 1);
+        assert!(*settled.lock().unwrap());
+
+        let second = profile(4.2, 25);
+        scope(Arc::clone(&buffer), async {
+            ft_mongodb_profiles::insert_profile_in_mongo(&client, &second, 42).await
+        })
+        .await?;
+        let report = buffer.flush().await?;
+        assert_eq!(report.written, 3);
+        assert!(report.failed.is_empty());
+
+        let stored = profiles.find_one(doc! {"_id": 42}).await?.unwrap();
+        assert!(matches!(
+            stored.get("wallet"),
+            Some(Bson::Int32(25) | Bson::Int64(25))
+        ));
+        let history: Vec<Document> = data_collection::<Document>(&client, PROFILES_HISTORY)
+            .find(doc! {})
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(history.len(), 1);
+        let changed = history[0].get_document("changed")?;
+        assert!(matches!(
+            changed.get("wallet"),
+            Some(Bson::Int32(10) | Bson::Int64(10))
+        ));
+        assert!(changed.contains_key("cursus_users"));
+        let changes: Vec<Document> = data_collection::<Document>(&client, CHANGES)
+            .find(doc! {})
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].get_str("kind")?, "level_up");
+        assert_eq!(changes[0].get_i64("user_id")?, 42);
+        container.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_items_of_refused_writes_are_released() -> Result<(), Box<dyn Error>> {
+        let (client, container) = get_test_mongo_client().await;
+        let database = client.database("validated");
+        database
+            .create_collection("events")
+            .validator(doc! {"$jsonSchema": {"required": ["name"]}})
+            .await?;
+        let events: Collection<Document> = database.collection("events");
+        let queue = WorkQueue::<EventId>::new(&client, EVENT_QUEUE);
+        queue.push(&EventId(1), &QueuePriority::default()).await?;
+        queue.push(&EventId(2), &QueuePriority::default()).await?;
+        let claimed = queue.claim(2).await?;
+        let buffer = Arc::new(WriteBuffer::new(&client));
+        scope(Arc::clone(&buffer), async {
+            let valid = vec![doc! {"_id": 1, "name": "exam"}];
+            queue.for_item(1, replace(&client, &events, valid)).await?;
+            let invalid = vec![doc! {"_id": 2}];
+            queue
+                .for_item(2, replace(&client, &events, invalid))
+                .await?;
+            let outcomes = vec![(1, IndexOutcome::Done), (2, IndexOutcome::Done)];
+            queue
+                .settle_after_flush(claimed.lease_token, outcomes)
+                .await
+        })
+        .await?;
+        let report = buffer.flush().await?;
+        assert_eq!(report.failed.len(), 1);
+        let left: Vec<Document> = config::app_collection::<Document>(&client, EVENT_QUEUE)
+            .find(doc! {})
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].get("_id"), Some(&Bson::Int64(2)));
+        assert_eq!(left[0].get_i32("attempts")?, 1);
+        assert!(!left[0].contains_key("lease_token"));
+        container.stop().await?;
+        Ok(())
+    }
+}
@@ -1,52 +1,475 @@
-use fetching_profile::TIME_BETWEEN_REQUESTS;
+use clap::{Args, CommandFactory, Parser, Subcommand, error::ErrorKind};
+use config::{AppConfig, CredentialsConfig};
+use fetching_concurrent::QuotaAllocator;
+use fetching_pipeline::Pipeline;
 use ft_api::generate_access_token;
+use ft_mongodb_app_queue_stats::QueueStats;
+use ft_mongodb_app_runs::RunJournal;
+use ft_mongodb_app_seeder::{SeedDedupe, SeedTarget};
+use ft_mongodb_app_workers::Worker;
+use ft_mongodb_indexes::IndexReport;
 use ft_mongodb_mode::Mode;
-use log::{debug, error, info};
+use ft_mongodb_write_buffer::WriteBuffer;
+use log::{error, info};
+use mongodb::bson::Bson;
 use oauth2::AccessToken;
-use std::{env, error::Error, time::Duration};
-use tokio::time::{Instant, sleep_until};
-
-use crate::{fetching_event_participation::double_fetch_events_participation_from_42_to_mongo, fetching_new_profiles_ids::fetch_profils_ids_from_42_to_mongo};
+use pipeline_config::PipelineConfig;
+use shutdown::Shutdown;
+use std::{error::Error, path::PathBuf, sync::Arc, time::Duration};
+use tokio::time::Instant;
 
+pub mod config;
+pub mod fetching_concurrent;
+pub mod fetching_dispatcher;
 pub mod fetching_event;
 pub mod fetching_event_participation;
 pub mod fetching_locations;
 pub mod fetching_new_profiles_ids;
+pub mod fetching_pipeline;
 pub mod fetching_profile;
 pub mod ft_api;
 pub mod ft_mongodb;
 pub mod ft_mongodb_app_indexor;
 pub mod ft_mongodb_app_new_profile_index;
+pub mod ft_mongodb_app_queue_stats;
+pub mod ft_mongodb_app_refresh;
+pub mod ft_mongodb_app_runs;
+pub mod ft_mongodb_app_seeder;
+pub mod ft_mongodb_app_workers;
+pub mod ft_mongodb_changes;
+pub mod ft_mongodb_collections;
+pub mod ft_mongodb_dry_run;
 pub mod ft_mongodb_events;
 pub mod ft_mongodb_events_participation;
+pub mod ft_mongodb_export;
+pub mod ft_mongodb_indexes;
 pub mod ft_mongodb_last_update;
 pub mod ft_mongodb_locations;
+pub mod ft_mongodb_migrations;
 pub mod ft_mongodb_mode;
 pub mod ft_mongodb_profile_indexer;
 pub mod ft_mongodb_profiles;
+pub mod ft_mongodb_write_buffer;
+pub mod pipeline_config;
+pub mod run_budget;
+pub mod shutdown;
+pub mod slimming;
 
-pub const NB_MINUTES: u32 = 10;
-pub const NB_FETCH: u32 = (NB_MINUTES * 60) / TIME_BETWEEN_REQUESTS;
 pub const MAX_INDEX: u32 = 207864;
+/// 42 access tokens last two hours, the daemon renews them well before.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Pause of the daemon after a round that found nothing to fetch.
+pub const DAEMON_IDLE_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    options: SharedOptions,
+}
+
+impl Cli {
+    /// Refuses the combinations of arguments clap cannot express.
+    fn check(&self) -> Result<(), clap::Error> {
+        if let Some(Command::Fetch {
+            pipeline,
+            reserve: true,
+        }) = &self.command
+            && *pipeline != Pipeline::Profiles
+        {
+            return Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--reserve only applies to `fetch profiles`",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Options shared by every command, each overriding its counterpart in the
+/// config file and the environment.
+#[derive(Args)]
+struct SharedOptions {
+    /// TOML config file, instead of FT_CONFIG or ./ft_connections.toml.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Connection string of MongoDB, instead of MONGODB_URI.
+    #[arg(long, global = true)]
+    mongodb_uri: Option<String>,
+    /// Put in front of every collection name, instead of FT_COLLECTION_PREFIX.
+    #[arg(long, global = true)]
+    collection_prefix: Option<String>,
+    /// Stages walked by the dispatcher, the bundled pipeline.toml when omitted.
+    #[arg(long = "pipeline", id = "pipeline_config", global = true)]
+    pipeline: Option<PathBuf>,
+    /// Most double fetches a run makes.
+    #[arg(long, global = true)]
+    fetches: Option<u32>,
+    /// Fetch and transform without writing to MongoDB: documents are printed
+    /// as JSON lines and queues are only peeked at.
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Wall-clock length of a run, like 90s, 10m or 1h. Runs last 10m when
+    /// neither this nor --fetches is given.
+    #[arg(long, global = true, value_parser = run_budget::parse_duration)]
+    deadline: Option<Duration>,
+    /// Client id of the profiles application, instead of SECRET_ID_PROFIL.
+    #[arg(long, global = true)]
+    profil_id: Option<String>,
+    /// Secret of the profiles application, instead of SECRET_KEY_PROFIL.
+    #[arg(long, global = true)]
+    profil_secret: Option<String>,
+    /// Client id of the locations application, instead of SECRET_ID_LOCATION.
+    #[arg(long, global = true)]
+    location_id: Option<String>,
+    /// Secret of the locations application, instead of SECRET_KEY_LOCATION.
+    #[arg(long, global = true)]
+    location_secret: Option<String>,
+}
+
+impl SharedOptions {
+    /// Lays the flags given on the command line over `config`.
+    fn apply(&self, config: &mut AppConfig) {
+        let overrides = [
+            (&self.mongodb_uri, &mut config.mongodb.uri),
+            (&self.profil_id, &mut config.credentials.profil_id),
+            (&self.profil_secret, &mut config.credentials.profil_secret),
+            (&self.location_id, &mut config.credentials.location_id),
+            (
+                &self.location_secret,
+                &mut config.credentials.location_secret,
+            ),
+        ];
+        for (flag, value) in overrides {
+            if flag.is_some() {
+                value.clone_from(flag);
+            }
+        }
+        if let Some(prefix) = &self.collection_prefix {
+            config.mongodb.collection_prefix.clone_from(prefix);
+        }
+        if self.pipeline.is_some() {
+            config.pipeline.clone_from(&self.pipeline);
+        }
+        if self.fetches.is_some() {
+            config.budget.fetches = self.fetches;
+        }
+        if self.deadline.is_some() {
+            config.budget.deadline = self.deadline;
+        }
+        if self.dry_run {
+            config.features.dry_run = true;
+        }
+    }
+}
 
-pub const CURRENT_MODE: Mode = Mode::UserEvents;
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch a resource from the 42 API, pulling ids from its work queue.
+    Fetch {
+        #[arg(value_enum)]
+        pipeline: Pipeline,
+        /// Queue the next range of user ids before fetching, profiles only.
+        #[arg(long)]
+        reserve: bool,
+    },
+    /// Fetch several resources at once, splitting the request slots between
+    /// them by their `quota` weights.
+    Concurrent {
+        /// Resources to fetch, every one with a non-zero weight when omitted.
+        #[arg(value_enum)]
+        pipelines: Vec<Pipeline>,
+    },
+    /// Refill a work queue from the data already stored in MongoDB.
+    Seed {
+        #[arg(value_enum)]
+        target: SeedTarget,
+        #[arg(long, value_enum, default_value_t = SeedDedupe::KeepExisting)]
+        dedupe: SeedDedupe,
+    },
+    /// Show depth, leases, dead letters, throughput and ETA of every queue.
+    Status,
+    /// Put stored documents back in their queue once they are stale.
+    Refresh,
+    /// Walk the pipeline round after round until SIGTERM or SIGINT.
+    Daemon,
+    /// Upgrade stored documents with the migrations not applied yet.
+    Migrate {
+        /// Only print where each migration stands.
+        #[arg(long)]
+        status: bool,
+    },
+    /// Read, change, pause or resume the current mode.
+    Mode {
+        #[command(subcommand)]
+        action: ModeAction,
+    },
+    /// Create the declared indexes that are missing and list the undeclared
+    /// ones, which are never dropped.
+    Indexes,
+    /// Dump a collection as JSON lines.
+    Export {
+        /// Logical name, renamed and prefixed like every collection.
+        collection: String,
+        /// Database to read from, the data database when omitted.
+        #[arg(long)]
+        database: Option<String>,
+        /// File to write to, stdout when omitted.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        limit: Option<i64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModeAction {
+    /// Print the current mode and whether it is paused.
+    Get,
+    /// Move to another stage of the pipeline, refused while paused.
+    Set {
+        #[arg(value_enum)]
+        mode: Mode,
+        #[arg(long, default_value = "manual")]
+        reason: String,
+    },
+    /// Move to any mode, paused or not.
+    Force {
+        #[arg(value_enum)]
+        mode: Mode,
+        #[arg(long, default_value = "manual")]
+        reason: String,
+    },
+    /// Move to the next stage of the pipeline.
+    Skip {
+        #[arg(long, default_value = "manual")]
+        reason: String,
+    },
+    /// Stop dispatching until resumed.
+    Pause {
+        #[arg(long, default_value = "manual")]
+        reason: String,
+    },
+    Resume {
+        #[arg(long, default_value = "manual")]
+        reason: String,
+    },
+    /// Print the last transitions, newest first.
+    History {
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    let cli = Cli::parse();
+    if let Err(e) = cli.check() {
+        e.exit();
+    }
     info!("Starting 42 analytics.");
-    let (client, api_key_1, api_key_2) = initialize_variables().await?;
-
-    // update event participations
-    for _ in 0..NB_FETCH {
-        let start = Instant::now();
-        double_fetch_events_participation_from_42_to_mongo(&client, &api_key_1, &api_key_2).await?;
-        let end = Instant::now();
-        let elapsed = end.duration_since(start);
-        if elapsed < Duration::from_secs(TIME_BETWEEN_REQUESTS as u64) {
-            let wait_duration = Duration::from_secs(TIME_BETWEEN_REQUESTS as u64) - elapsed;
-            debug!("Waiting for {:?} before next fetch.", wait_duration);
-            sleep_until(Instant::now() + wait_duration).await;
+    let mut config = AppConfig::load(cli.options.config.as_deref())?;
+    cli.options.apply(&mut config);
+    config.validate()?;
+    let config = config.install()?;
+    let pipeline = PipelineConfig::load(config.pipeline.as_deref())?;
+    let client = initialize_client(config).await?;
+    if config.features.ensure_indexes
+        && !matches!(cli.command, Some(Command::Indexes))
+        && let Err(e) = ensure_indexes(&client).await
+    {
+        error!("Indexes not reconciled: {}", e);
+    }
+    let shutdown = Shutdown::listen();
+
+    match cli.command {
+        Some(Command::Fetch { pipeline, reserve }) => {
+            journaled(&client, config, "fetch", async {
+                let (api_key_1, api_key_2) = initialize_tokens(&config.credentials).await?;
+                with_worker(config, &client, async {
+                    if reserve {
+                        fetching_profile::fetch_profiles_from_42_to_mongodb(
+                            &client,
+                            &api_key_1,
+                            &api_key_2,
+                            &config.run_budget(),
+                            &shutdown,
+                        )
+                        .await
+                    } else {
+                        fetching_pipeline::run_pipeline(
+                            &client,
+                            pipeline,
+                            &api_key_1,
+                            &api_key_2,
+                            &config.run_budget(),
+                            &shutdown,
+                        )
+                        .await
+                    }
+                })
+                .await
+            })
+            .await?;
+        }
+        Some(Command::Concurrent { pipelines }) => {
+            let weights: Vec<_> = config
+                .quota
+                .weights()
+                .into_iter()
+                .filter(|(pipeline, _)| pipelines.is_empty() || pipelines.contains(pipeline))
+                .collect();
+            if weights.is_empty() {
+                return Err("None of these resources has a quota weight".into());
+            }
+            journaled(&client, config, "concurrent", async {
+                let (api_key_1, api_key_2) = initialize_tokens(&config.credentials).await?;
+                with_worker(
+                    config,
+                    &client,
+                    fetching_concurrent::run_concurrently(
+                        &client,
+                        QuotaAllocator::new(&weights),
+                        &api_key_1,
+                        &api_key_2,
+                        &config.run_budget(),
+                        &shutdown,
+                    ),
+                )
+                .await
+            })
+            .await?;
+        }
+        Some(Command::Seed { target, dedupe }) => {
+            let dry_run = config.features.dry_run;
+            let count = ft_mongodb_app_seeder::seed_queue(&client, target, dedupe, dry_run).await?;
+            println!("{} {}", target.queue_name(), count);
+        }
+        Some(Command::Status) => {
+            let all_stats = ft_mongodb_app_queue_stats::get_all_queue_stats(&client).await?;
+            print_queue_stats(&all_stats);
+            let workers = ft_mongodb_app_workers::get_alive_workers(&client).await?;
+            println!("{} alive workers", workers.len());
+            for worker in workers {
+                println!("{}", worker);
+            }
+        }
+        Some(Command::Refresh) => {
+            let dry_run = config.features.dry_run;
+            let policies = &ft_mongodb_app_refresh::DEFAULT_REFRESH_POLICIES;
+            let swept = ft_mongodb_app_refresh::sweep_all_stale(&client, policies, dry_run).await?;
+            for (policy, count) in swept {
+                println!("{} {}", policy.resource.queue_name(), count);
+            }
+        }
+        Some(Command::Indexes) => {
+            let report = ensure_indexes(&client).await?;
+            let missing = match config.features.dry_run {
+                true => "missing",
+                false => "created",
+            };
+            for name in report.missing {
+                println!("{} {}", missing, name);
+            }
+            for name in report.extra {
+                println!("extra {}", name);
+            }
+            for namespace in &report.failed {
+                println!("failed {}", namespace);
+            }
+            if !report.failed.is_empty() {
+                return Err(format!("Indexes of {} not created", report.failed.join(", ")).into());
+            }
+        }
+        Some(Command::Migrate { status: true }) => {
+            for status in ft_mongodb_migrations::get_migration_statuses(&client).await? {
+                let state = match status.completed {
+                    true => "completed",
+                    false => "pending",
+                };
+                println!(
+                    "{} {} {} {}",
+                    status.version, status.name, state, status.migrated
+                );
+            }
+        }
+        Some(Command::Migrate { status: false }) => {
+            let migrations = &ft_mongodb_migrations::MIGRATIONS;
+            for (version, migrated) in
+                ft_mongodb_migrations::run_migrations(&client, migrations).await?
+            {
+                println!("{} {}", version, migrated);
+            }
+            // Some indexes, like unique ones, only fit the migrated shape.
+            if config.features.ensure_indexes {
+                ensure_indexes(&client).await?;
+            }
+        }
+        Some(Command::Mode { action }) => run_mode_action(&client, &pipeline, action).await?,
+        Some(Command::Export {
+            collection,
+            database,
+            output,
+            limit,
+        }) => {
+            let database = database.as_deref().unwrap_or(&config.mongodb.data_database);
+            let collection = config::collection_name(&collection);
+            let count = match output {
+                Some(path) => {
+                    let mut file = tokio::fs::File::create(path).await?;
+                    ft_mongodb_export::export_collection(
+                        &client,
+                        database,
+                        &collection,
+                        limit,
+                        &mut file,
+                    )
+                    .await?
+                }
+                None => {
+                    let mut stdout = tokio::io::stdout();
+                    ft_mongodb_export::export_collection(
+                        &client,
+                        database,
+                        &collection,
+                        limit,
+                        &mut stdout,
+                    )
+                    .await?
+                }
+            };
+            info!("{} documents exported.", count);
+        }
+        Some(Command::Daemon) => {
+            with_worker(
+                config,
+                &client,
+                run_daemon(&client, config, &pipeline, &shutdown),
+            )
+            .await?;
+        }
+        None => {
+            journaled(&client, config, "dispatch", async {
+                let (api_key_1, api_key_2) = initialize_tokens(&config.credentials).await?;
+                refresh_if_enabled(&client, config).await?;
+                with_worker(
+                    config,
+                    &client,
+                    fetching_dispatcher::run_dispatcher(
+                        &client,
+                        &pipeline,
+                        &api_key_1,
+                        &api_key_2,
+                        &config.run_budget(),
+                        &shutdown,
+                    ),
+                )
+                .await
+            })
+            .await?;
         }
     }
 
@@ -54,32 +477,257 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn initialize_variables()
--> Result<(mongodb::Client, AccessToken, AccessToken), Box<dyn Error>> {
-    let mongodb_uri = get_var_env("MONGODB_URI");
-    let client = ft_mongodb::connect_to_mongodb(&mongodb_uri).await;
-    let secret_key_profil = generate_access_token(
-        &get_var_env("SECRET_ID_PROFIL"),
-        &get_var_env("SECRET_KEY_PROFIL"),
-    )
-    .await?;
-    let secret_key_location = generate_access_token(
-        &get_var_env("SECRET_ID_LOCATION"),
-        &get_var_env("SECRET_KEY_LOCATION"),
-    )
-    .await?;
-    Ok((client, secret_key_profil, secret_key_location))
-}
-
-fn get_var_env(env_name: &str) -> String {
-    match env::var(env_name) {
-        Ok(val) => {
-            debug!("Retrieved {} from environment.", env_name);
-            val
-        }
-        Err(e) => {
-            error!("{} environment variable not set: {}", env_name, e);
-            panic!();
+/// Runs `job` while this process is registered as a live worker, unless
+/// `features.register_worker` is off, then writes what is still buffered and
+/// puts back whatever it still holds a lease on. A dry run holds no lease,
+/// buffers and registers nothing.
+async fn with_worker<T>(
+    config: &AppConfig,
+    client: &mongodb::Client,
+    job: impl Future<Output = Result<T, Box<dyn Error>>>,
+) -> Result<T, Box<dyn Error>> {
+    if config.features.dry_run {
+        return job.await;
+    }
+    let registration = match config.features.register_worker {
+        true => {
+            let worker = Worker::register(client).await?;
+            let heartbeat = worker.spawn_heartbeat();
+            Some((worker, heartbeat))
+        }
+        false => None,
+    };
+    let buffer = Arc::new(WriteBuffer::new(client));
+    let flusher = buffer.spawn_flusher();
+    let result = ft_mongodb_write_buffer::scope(Arc::clone(&buffer), job).await;
+    flusher.stop().await;
+    // Each step runs even if the one before failed.
+    let flushed = buffer.flush().await;
+    let released =
+        ft_mongodb_app_workers::release_leases(client, ft_mongodb_app_workers::worker_id()).await;
+    let unregistered = match registration {
+        Some((worker, heartbeat)) => {
+            heartbeat.stop().await;
+            worker.unregister().await
+        }
+        None => Ok(()),
+    };
+    flushed?;
+    released?;
+    unregistered?;
+    result
+}
+
+async fn run_mode_action(
+    client: &mongodb::Client,
+    pipeline: &PipelineConfig,
+    action: ModeAction,
+) -> Result<(), Box<dyn Error>> {
+    let state = match action {
+        ModeAction::Get => ft_mongodb_mode::get_mode_state(client).await?,
+        ModeAction::Set { mode, reason } => {
+            ft_mongodb_mode::set_mode_in_mongo(client, pipeline, mode, &reason).await?
+        }
+        ModeAction::Force { mode, reason } => {
+            ft_mongodb_mode::force_mode(client, mode, &reason).await?
+        }
+        ModeAction::Skip { reason } => {
+            ft_mongodb_mode::skip_mode(client, pipeline, &reason).await?
+        }
+        ModeAction::Pause { reason } => ft_mongodb_mode::pause_mode(client, &reason).await?,
+        ModeAction::Resume { reason } => ft_mongodb_mode::resume_mode(client, &reason).await?,
+        ModeAction::History { limit } => {
+            for entry in ft_mongodb_mode::get_mode_history(client, limit).await? {
+                println!("{}", Bson::Document(entry).into_relaxed_extjson());
+            }
+            return Ok(());
+        }
+    };
+    match state.paused {
+        true => println!("{} (paused)", state.mode.as_str()),
+        false => println!("{}", state.mode.as_str()),
+    }
+    Ok(())
+}
+
+/// Reconciles the indexes of `ft_mongodb_indexes::declared_indexes`.
+async fn ensure_indexes(client: &mongodb::Client) -> Result<IndexReport, Box<dyn Error>> {
+    let report =
+        ft_mongodb_indexes::reconcile_indexes(client, &ft_mongodb_indexes::declared_indexes())
+            .await?;
+    info!(
+        "{} declared indexes missing, {} undeclared, {} collections failed.",
+        report.missing.len(),
+        report.extra.len(),
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// Runs `job` as one entry of the run journal. Failing to journal is logged
+/// but never hides the outcome of `job`.
+async fn journaled<T>(
+    client: &mongodb::Client,
+    config: &AppConfig,
+    command: &str,
+    job: impl Future<Output = Result<T, Box<dyn Error>>>,
+) -> Result<T, Box<dyn Error>> {
+    let journal = RunJournal::start(client, command, config).await;
+    let result = job.await;
+    match journal {
+        Ok(journal) => {
+            if let Err(e) = journal.finish(&result).await {
+                error!("Run journal not finished: {}", e);
+            }
+        }
+        Err(e) => error!("Run journal not started: {}", e),
+    }
+    result
+}
+
+/// Puts stale documents back in their queues when `features.refresh_before_run`
+/// is on.
+async fn refresh_if_enabled(
+    client: &mongodb::Client,
+    config: &AppConfig,
+) -> Result<(), Box<dyn Error>> {
+    if !config.features.refresh_before_run {
+        return Ok(());
+    }
+    let policies = &ft_mongodb_app_refresh::DEFAULT_REFRESH_POLICIES;
+    for (policy, count) in
+        ft_mongodb_app_refresh::sweep_all_stale(client, policies, config.features.dry_run).await?
+    {
+        info!(
+            "{} stale ids requeued in {}.",
+            count,
+            policy.resource.queue_name()
+        );
+    }
+    Ok(())
+}
+
+/// Walks the pipeline round after round until a shutdown is requested, each
+/// round with a fresh budget and its own entry in the run journal. A failed
+/// round is logged and retried after `DAEMON_IDLE_WAIT`.
+async fn run_daemon(
+    client: &mongodb::Client,
+    config: &AppConfig,
+    pipeline: &PipelineConfig,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    info!("Running as a daemon.");
+    let mut tokens: Option<(AccessToken, AccessToken, Instant)> = None;
+    while !shutdown.is_requested() {
+        let (api_key_1, api_key_2, _) = match tokens {
+            Some(ref tokens) if tokens.2.elapsed() < TOKEN_LIFETIME => tokens,
+            _ => match initialize_tokens(&config.credentials).await {
+                Ok((api_key_1, api_key_2)) => tokens.insert((api_key_1, api_key_2, Instant::now())),
+                Err(e) => {
+                    error!("Failed to refresh the 42 API tokens: {}", e);
+                    shutdown.sleep(DAEMON_IDLE_WAIT).await;
+                    continue;
+                }
+            },
+        };
+        let round = journaled(client, config, "daemon", async {
+            refresh_if_enabled(client, config).await?;
+            fetching_dispatcher::run_dispatcher(
+                client,
+                pipeline,
+                api_key_1,
+                api_key_2,
+                &config.run_budget(),
+                shutdown,
+            )
+            .await
+        })
+        .await;
+        match round {
+            Ok(0) => {
+                info!("Nothing to fetch, waiting {:?}.", DAEMON_IDLE_WAIT);
+                shutdown.sleep(DAEMON_IDLE_WAIT).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Daemon round failed: {}", e);
+                shutdown.sleep(DAEMON_IDLE_WAIT).await;
+            }
         }
     }
+    info!("Daemon stopped.");
+    Ok(())
+}
+
+fn print_queue_stats(all_stats: &[QueueStats]) {
+    println!(
+        "{:<30} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "queue", "depth", "in_flight", "dead", "per_hour", "eta"
+    );
+    for stats in all_stats {
+        let eta = match stats.eta {
+            Some(eta) => format!("{}h{:02}m", eta.as_secs() / 3600, eta.as_secs() % 3600 / 60),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<30} {:>10} {:>10} {:>10} {:>10.1} {:>12}",
+            stats.queue,
+            stats.depth,
+            stats.in_flight,
+            stats.dead_letters,
+            stats.throughput_per_hour,
+            eta
+        );
+    }
+}
+
+async fn initialize_client(config: &AppConfig) -> Result<mongodb::Client, Box<dyn Error>> {
+    let mongodb_uri = config.mongodb.uri.as_deref().unwrap_or_default();
+    ft_mongodb::connect_to_mongodb(mongodb_uri).await
+}
+
+async fn initialize_tokens(
+    credentials: &CredentialsConfig,
+) -> Result<(AccessToken, AccessToken), Box<dyn Error>> {
+    let credentials = credentials.require()?;
+    let secret_key_profil =
+        generate_access_token(credentials.profil_id, credentials.profil_secret).await?;
+    let secret_key_location =
+        generate_access_token(credentials.location_id, credentials.location_secret).await?;
+    Ok((secret_key_profil, secret_key_location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_parses_next_to_the_pipeline_option() {
+        let cli = Cli::try_parse_from([
+            "ft_connections",
+            "fetch",
+            "events",
+            "--pipeline",
+            "stages.toml",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Fetch {
+                pipeline: Pipeline::Events,
+                reserve: false,
+            })
+        ));
+        assert_eq!(cli.options.pipeline, Some(PathBuf::from("stages.toml")));
+    }
+
+    #[test]
+    fn test_reserve_is_only_accepted_for_profiles() {
+        let parse = |pipeline| {
+            Cli::try_parse_from(["ft_connections", "fetch", pipeline, "--reserve"]).unwrap()
+        };
+        assert!(parse("profiles").check().is_ok());
+        let error = parse("events").check().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+    }
 }
@@ -0,0 +1,249 @@
+use std::{error::Error, path::Path};
+
+use log::info;
+use oauth2::AccessToken;
+use serde::Deserialize;
+
+use crate::fetching_pipeline::Pipeline;
+use crate::ft_mongodb_app_seeder::SeedTarget;
+use crate::ft_mongodb_mode::Mode;
+
+/// Stages shipped with the binary, used when no file is given.
+const DEFAULT_PIPELINE: &str = include_str!("../pipeline.toml");
+
+/// Which 42 applications a stage spends its requests on.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Credentials {
+    #[default]
+    Both,
+    Profil,
+    Location,
+}
+
+/// When the dispatcher moves past a stage.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Advance {
+    /// Once the resource queue is empty, leased and delayed items included.
+    #[default]
+    Drained,
+    /// Once a run has spent the whole budget of the stage.
+    BudgetSpent,
+    /// After every run.
+    Always,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StageConfig {
+    pub mode: Mode,
+    pub resource: Option<Pipeline>,
+    #[serde(default)]
+    pub seed: Vec<SeedTarget>,
+    #[serde(default)]
+    pub reserve_profiles: bool,
+    #[serde(default)]
+    pub then_seed: Vec<SeedTarget>,
+    pub budget: Option<u32>,
+    #[serde(default)]
+    pub credentials: Credentials,
+    #[serde(default)]
+    pub advance: Advance,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub stages: Vec<StageConfig>,
+}
+
+impl Credentials {
+    /// Tokens for the two requests of a double fetch.
+    pub fn pick<'a>(
+        &self,
+        profil: &'a AccessToken,
+        location: &'a AccessToken,
+    ) -> (&'a AccessToken, &'a AccessToken) {
+        match self {
+            Credentials::Both => (profil, location),
+            Credentials::Profil => (profil, profil),
+            Credentials::Location => (location, location),
+        }
+    }
+}
+
+impl StageConfig {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let mode = self.mode.as_str();
+        if self.budget == Some(0) {
+            return Err(format!("Stage {} has a budget of 0", mode).into());
+        }
+        if self.advance == Advance::BudgetSpent && self.budget.is_none() {
+            return Err(format!("Stage {} advances on its budget but has none", mode).into());
+        }
+        match self.resource {
+            Some(_) => {
+                if !self.seed.is_empty() || self.reserve_profiles {
+                    return Err(format!(
+                        "Stage {} fetches a resource, use then_seed instead of seed",
+                        mode
+                    )
+                    .into());
+                }
+                if !self.then_seed.is_empty() && self.advance != Advance::Drained {
+                    return Err(format!(
+                        "Stage {} uses then_seed, it must advance when drained",
+                        mode
+                    )
+                    .into());
+                }
+            }
+            None => {
+                if self.seed.is_empty() && !self.reserve_profiles {
+                    return Err(format!("Stage {} has nothing to fetch or seed", mode).into());
+                }
+                if !self.then_seed.is_empty() || self.budget.is_some() {
+                    return Err(format!(
+                        "Stage {} has no resource, then_seed and budget need one",
+                        mode
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PipelineConfig {
+    /// Reads the stages from `path`, or the default stages without one, and
+    /// refuses any configuration the dispatcher could not walk.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let config = match path {
+            Some(path) => {
+                info!("Loading pipeline from {}.", path.display());
+                Self::parse(&std::fs::read_to_string(path)?)?
+            }
+            None => Self::parse(DEFAULT_PIPELINE)?,
+        };
+        info!("Pipeline has {} stages.", config.stages.len());
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let config: PipelineConfig = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.stages.iter().any(|stage| stage.resource.is_some()) {
+            return Err("Pipeline needs at least one stage with a resource".into());
+        }
+        for (i, stage) in self.stages.iter().enumerate() {
+            stage.validate()?;
+            if self.stages[..i]
+                .iter()
+                .any(|other| other.mode == stage.mode)
+            {
+                return Err(format!("Stage {} appears twice", stage.mode.as_str()).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stage(&self, mode: Mode) -> Option<&StageConfig> {
+        self.stages.iter().find(|stage| stage.mode == mode)
+    }
+
+    pub fn first_mode(&self) -> Mode {
+        self.stages[0].mode
+    }
+
+    /// Stage after `current`, wrapping around. A mode that is not part of
+    /// the pipeline restarts it from the first stage.
+    pub fn next_mode(&self, current: Mode) -> Mode {
+        match self.stages.iter().position(|stage| stage.mode == current) {
+            Some(i) => self.stages[(i + 1) % self.stages.len()].mode,
+            None => self.first_mode(),
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self::parse(DEFAULT_PIPELINE).expect("pipeline.toml is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_pipeline_keeps_the_historical_cycle() {
+        let config = PipelineConfig::default();
+        let mut mode = config.first_mode();
+        let mut cycle = vec![mode];
+        for _ in 1..config.stages.len() {
+            mode = config.next_mode(mode);
+            cycle.push(mode);
+        }
+        assert_eq!(
+            cycle,
+            vec![
+                Mode::ProfilesIndexing,
+                Mode::Profiles,
+                Mode::LocationsIndexing,
+                Mode::Locations,
+                Mode::UserEvents,
+                Mode::Events,
+            ]
+        );
+        assert_eq!(config.next_mode(Mode::Events), Mode::ProfilesIndexing);
+    }
+
+    #[test]
+    fn test_skipped_mode_restarts_from_first_stage() {
+        let config = PipelineConfig::parse(
+            r#"
+            [[stages]]
+            mode = "events"
+            resource = "events"
+            budget = 50
+            credentials = "location"
+
+            [[stages]]
+            mode = "locations"
+            resource = "locations"
+            advance = "always"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.next_mode(Mode::Events), Mode::Locations);
+        assert_eq!(config.next_mode(Mode::Locations), Mode::Events);
+        assert_eq!(config.next_mode(Mode::Profiles), Mode::Events);
+        assert_eq!(config.stages[0].credentials, Credentials::Location);
+    }
+
+    #[test]
+    fn test_invalid_pipelines_are_rejected() {
+        let invalid = [
+            "stages = []",
+            "[[stages]]\nmode = \"events\"\nresource = \"event\"",
+            "[[stages]]\nmode = \"events\"\nresource = \"events\"\nbudget = 0",
+            "[[stages]]\nmode = \"events\"\nresource = \"events\"\n\
+             [[stages]]\nmode = \"events\"\nresource = \"events\"",
+            "[[stages]]\nmode = \"events\"\nresource = \"events\"\n\
+             then_seed = [\"events\"]\nadvance = \"always\"",
+            "[[stages]]\nmode = \"events\"\nresource = \"events\"\n\
+             advance = \"budget_spent\"",
+            "[[stages]]\nmode = \"profiles_indexing\"\n\
+             [[stages]]\nmode = \"events\"\nresource = \"events\"",
+        ];
+        for content in invalid {
+            assert!(PipelineConfig::parse(content).is_err(), "{}", content);
+        }
+    }
+}
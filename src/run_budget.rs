@@ -0,0 +1,148 @@
+use std::{error::Error, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::config;
+
+/// Run length when neither a deadline nor a fetch count is given, one short
+/// of the 11-minute cron so runs never overlap.
+pub const DEFAULT_RUN_DURATION: Duration = Duration::from_secs(10 * 60);
+/// No fetch starts closer than this to the deadline, enough for the
+/// requests in flight to end and their items to be settled.
+pub const DEADLINE_MARGIN: Duration = Duration::from_secs(15);
+/// Longest duration `parse_duration` accepts, far beyond any run and short
+/// enough for a deadline counted from now to always be representable.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How much work a run may still take: a number of double fetches, a
+/// wall-clock deadline, or both. At least one of them is always set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunBudget {
+    fetches: Option<u32>,
+    deadline: Option<Instant>,
+}
+
+impl RunBudget {
+    /// Starts the clock now. Without any limit the run lasts
+    /// `DEFAULT_RUN_DURATION`.
+    pub fn new(fetches: Option<u32>, duration: Option<Duration>) -> Self {
+        let duration = match (fetches, duration) {
+            (None, None) => Some(DEFAULT_RUN_DURATION),
+            (_, duration) => duration,
+        };
+        RunBudget {
+            fetches,
+            deadline: duration.map(|duration| Instant::now() + duration),
+        }
+    }
+
+    /// Whether a fetch may start after `used` of them, with enough time left
+    /// to finish it before the deadline.
+    pub fn allows(&self, used: u32) -> bool {
+        let under_count = self.fetches.is_none_or(|fetches| used < fetches);
+        let under_deadline = self
+            .deadline
+            .is_none_or(|deadline| Instant::now() + DEADLINE_MARGIN <= deadline);
+        under_count && under_deadline
+    }
+
+    /// What is left once `used` fetches are spent.
+    pub fn after(&self, used: u32) -> Self {
+        RunBudget {
+            fetches: self.fetches.map(|fetches| fetches.saturating_sub(used)),
+            deadline: self.deadline,
+        }
+    }
+
+    /// The same budget, with at most `fetches` of them.
+    pub fn capped(&self, fetches: Option<u32>) -> Self {
+        let fetches = match (self.fetches, fetches) {
+            (Some(own), Some(cap)) => Some(own.min(cap)),
+            (own, cap) => own.or(cap),
+        };
+        RunBudget {
+            fetches,
+            deadline: self.deadline,
+        }
+    }
+
+    /// Fetches that fit in the budget at the paced rate, used to size the
+    /// ranges reserved up front.
+    pub fn estimated_fetches(&self) -> u32 {
+        let by_deadline = self.deadline.map(|deadline| {
+            let left = deadline
+                .saturating_duration_since(Instant::now())
+                .saturating_sub(DEADLINE_MARGIN);
+            (left.as_secs() / config::time_between_requests().as_secs()) as u32
+        });
+        match (self.fetches, by_deadline) {
+            (Some(fetches), Some(by_deadline)) => fetches.min(by_deadline),
+            (fetches, by_deadline) => fetches.or(by_deadline).unwrap_or(0),
+        }
+    }
+}
+
+/// Parses `90`, `90s`, `15m` or `2h` into a duration of at most
+/// `MAX_DURATION`.
+pub fn parse_duration(value: &str) -> Result<Duration, Box<dyn Error + Send + Sync>> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", value))?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("Invalid duration unit in '{}'", value).into()),
+    };
+    let duration = number
+        .checked_mul(factor)
+        .map(Duration::from_secs)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| format!("Invalid duration '{}', longer than a year", value))?;
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+        assert!(parse_duration("10000000000000000000").is_err());
+        assert_eq!(parse_duration("8760h").unwrap(), MAX_DURATION);
+        assert!(parse_duration("8761h").is_err());
+        let budget = RunBudget::new(None, Some(parse_duration("8760h").unwrap()));
+        assert!(budget.allows(0));
+    }
+
+    #[test]
+    fn test_fetch_count_budget() {
+        let budget = RunBudget::new(Some(3), None);
+        assert!(budget.allows(2));
+        assert!(!budget.allows(3));
+        assert_eq!(budget.after(1).capped(Some(5)).estimated_fetches(), 2);
+        assert_eq!(budget.capped(Some(1)).estimated_fetches(), 1);
+    }
+
+    #[test]
+    fn test_deadline_budget_stops_before_the_deadline() {
+        let budget = RunBudget::new(None, Some(DEADLINE_MARGIN / 2));
+        assert!(!budget.allows(0));
+        let budget = RunBudget::new(None, None);
+        assert!(budget.allows(1_000));
+        assert!(budget.estimated_fetches() > 0);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use tokio::{
+    sync::watch,
+    time::{Instant, sleep_until},
+};
+
+/// Tells long-running loops to stop taking new work. Clones share the same
+/// state, so one signal reaches every loop of the process.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    /// A shutdown that only happens through `request`.
+    pub fn new() -> Shutdown {
+        Shutdown {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Also requests the shutdown on SIGINT and, on unix, SIGTERM.
+    pub fn listen() -> Shutdown {
+        let shutdown = Shutdown::new();
+        let on_signal = shutdown.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!("Shutdown requested, finishing in-flight requests.");
+            on_signal.request();
+        });
+        shutdown
+    }
+
+    pub fn request(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once a shutdown is requested.
+    pub async fn requested(&self) {
+        let mut receiver = self.sender.subscribe();
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+
+    /// Sleeps until `deadline`, returning `false` early if a shutdown is
+    /// requested meanwhile.
+    pub async fn sleep_until(&self, deadline: Instant) -> bool {
+        tokio::select! {
+            _ = sleep_until(deadline) => true,
+            _ = self.requested() => false,
+        }
+    }
+
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_until(Instant::now() + duration).await
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sleep_is_cut_short_by_shutdown() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.sleep(Duration::from_millis(1)).await);
+        shutdown.clone().request();
+        assert!(shutdown.is_requested());
+        assert!(!shutdown.sleep(Duration::from_secs(3600)).await);
+    }
+}
@@ -0,0 +1,144 @@
+use mongodb::bson::{Bson, Document};
+
+use crate::config::{self, SlimRule};
+use crate::fetching_pipeline::Pipeline;
+
+/// Field every writer reads to build the `_id`, never slimmed away.
+const ID_FIELD: &str = "id";
+
+/// Trims `document`, as returned by the 42 API for `pipeline`, down to what
+/// `slimming` in the config says to store.
+pub fn slim(pipeline: Pipeline, document: &mut Document) {
+    apply_rule(config::get().slimming.rule(pipeline), document);
+}
+
+fn apply_rule(rule: &SlimRule, document: &mut Document) {
+    if let Some(keep) = &rule.keep {
+        let mut paths: Vec<&str> = keep.iter().map(String::as_str).collect();
+        paths.push(ID_FIELD);
+        keep_paths(document, &paths);
+    }
+    for path in &rule.drop {
+        if path != ID_FIELD {
+            drop_path(document, path);
+        }
+    }
+}
+
+/// Removes every field of `document` no path leads to or goes through.
+fn keep_paths(document: &mut Document, paths: &[&str]) {
+    let fields: Vec<String> = document.keys().cloned().collect();
+    for field in fields {
+        if paths.contains(&field.as_str()) {
+            continue;
+        }
+        let nested: Vec<&str> = paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(field.as_str())?.strip_prefix('.'))
+            .collect();
+        match (nested.is_empty(), document.get_mut(&field)) {
+            (false, Some(value)) => {
+                for_each_document(value, &mut |inner| keep_paths(inner, &nested))
+            }
+            _ => {
+                document.remove(&field);
+            }
+        }
+    }
+}
+
+fn drop_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+        }
+        Some((field, rest)) => {
+            if let Some(value) = document.get_mut(field) {
+                for_each_document(value, &mut |inner| drop_path(inner, rest));
+            }
+        }
+    }
+}
+
+/// Calls `f` on `value` if it is a document, or on each document of it if
+/// it is an array, so paths go through arrays of objects.
+fn for_each_document(value: &mut Bson, f: &mut impl FnMut(&mut Document)) {
+    match value {
+        Bson::Document(document) => f(document),
+        Bson::Array(items) => items.iter_mut().for_each(|item| for_each_document(item, f)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn profile() -> Document {
+        doc! {
+            "id": 42,
+            "login": "norminet",
+            "image": {"link": "https://cdn/norminet.jpg", "versions": {"large": "l", "small": "s"}},
+            "projects_users": [
+                {"id": 7, "final_mark": 100, "project": {"id": 1314, "slug": "libft", "name": "Libft"}},
+                {"id": 8, "final_mark": null, "project": {"id": 1315, "slug": "gnl", "name": "GNL"}},
+            ],
+            "titles": ["Mascot"],
+        }
+    }
+
+    #[test]
+    fn test_drop_goes_through_documents_and_arrays() {
+        let mut document = profile();
+        let rule = SlimRule {
+            keep: None,
+            drop: vec![
+                "image.versions".to_string(),
+                "projects_users.project.name".to_string(),
+                "id".to_string(),
+                "missing.field".to_string(),
+            ],
+        };
+        apply_rule(&rule, &mut document);
+        assert_eq!(
+            document.get_document("image").unwrap(),
+            &doc! {"link": "https://cdn/norminet.jpg"}
+        );
+        let project_users = document.get_array("projects_users").unwrap();
+        assert_eq!(
+            project_users[1]
+                .as_document()
+                .unwrap()
+                .get_document("project")
+                .unwrap(),
+            &doc! {"id": 1315, "slug": "gnl"}
+        );
+        assert_eq!(document.get_i32("id").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_keep_only_keeps_listed_paths_and_the_id() {
+        let mut document = profile();
+        let rule = SlimRule {
+            keep: Some(vec![
+                "login".to_string(),
+                "projects_users.project.slug".to_string(),
+                "projects_users.final_mark".to_string(),
+            ]),
+            drop: vec![],
+        };
+        apply_rule(&rule, &mut document);
+        assert_eq!(
+            document,
+            doc! {
+                "id": 42,
+                "login": "norminet",
+                "projects_users": [
+                    {"final_mark": 100, "project": {"slug": "libft"}},
+                    {"final_mark": null, "project": {"slug": "gnl"}},
+                ],
+            }
+        );
+    }
+}